@@ -1,10 +1,16 @@
 use anyhow::{anyhow, Result};
-use clap::{ArgMatches, Values};
+use clap::{ArgMatches, OsValues, Values};
 use rpassword;
 use std::path::PathBuf;
-use crate::icore::arg::{Arg, OverwriteStrategy, SendArg, RecvArg};
+use std::time::Duration;
+use crate::icore::arg::{Arg, ConnectMode, KeepAttr, OverwriteStrategy, RelayArg, SendArg, RecvArg};
+use crate::icore::{code, limit, relay};
 
 pub fn parse_input(m: &ArgMatches) -> Result<Arg> {
+    if let Some(r) = m.subcommand_matches("relay") {
+        return Ok(parse_relay_arg(r));
+    }
+
     let arg = match (m.occurrences_of("send"), m.occurrences_of("receive")) {
         (1, 0) => parse_send_arg(m)?,
         (0, 1) => parse_recv_arg(m)?,
@@ -16,10 +22,18 @@ pub fn parse_input(m: &ArgMatches) -> Result<Arg> {
 
 fn parse_send_arg(m: &ArgMatches) -> Result<Arg> {
     let send_arg = SendArg {
+        archive: m.occurrences_of("archive") > 0,
+        compress: m.occurrences_of("no-compress") == 0,
         expire: parse_expire(m),
         files: parse_sending_files(m),
+        interfaces: parse_interfaces(m),
+        limit: parse_limit(m)?,
+        mode: parse_mode(m),
         msg: parse_msg(m),
         password: parse_password(m),
+        retry: parse_retry(m)?,
+        streams: parse_streams(m)?,
+        timeout: parse_timeout(m)?,
     };
 
     if send_arg.msg.is_some() || send_arg.files.is_some() {
@@ -30,7 +44,12 @@ fn parse_send_arg(m: &ArgMatches) -> Result<Arg> {
 }
 
 fn parse_recv_arg(m: &ArgMatches) -> Result<Arg> {
-    let code = parse_code(m)?;
+    let mode = parse_mode(m);
+    let code = match mode {
+        ConnectMode::Discover | ConnectMode::Relay(_) => parse_code(m)?,
+        _ if m.values_of("INPUT").is_some() => return Err(anyhow!("No code needed when connecting directly")),
+        _ => String::new(),
+    };
     let dir = match parse_dir(m) {
         Some(d) => d,
         None => std::env::current_dir().expect("Cannot get current dir"),
@@ -38,21 +57,40 @@ fn parse_recv_arg(m: &ArgMatches) -> Result<Arg> {
 
     let recv_arg = RecvArg {
         code,
+        daemon: m.occurrences_of("daemon") > 0,
         dir,
         expire: parse_expire(m),
+        interfaces: parse_interfaces(m),
+        keep: parse_keep(m),
+        limit: parse_limit(m)?,
+        max_files: parse_max_files(m)?,
+        max_size: parse_max_size(m)?,
+        mode,
         overwrite: parse_overwrite(m),
         password: parse_password(m),
+        resume: m.occurrences_of("resume") > 0,
+        timeout: parse_timeout(m)?,
+        yes: m.occurrences_of("yes") > 0,
     };
 
     Ok(Arg::R(recv_arg))
 }
 
+fn parse_relay_arg(m: &ArgMatches) -> Arg {
+    let listen = match m.value_of("listen") {
+        Some(addr) => listen_addr(addr),
+        None => format!("0.0.0.0:{}", relay::DEFAULT_PORT),
+    };
+
+    Arg::Relay(RelayArg { listen })
+}
+
 fn parse_password(m: &ArgMatches) -> Option<String> {
     if m.occurrences_of("password") > 0 {
         loop {
             let pw = rpassword::read_password_from_tty(Some("Please enter the password: ")).unwrap();
             let pw_str = pw.trim();
-            if pw_str.len() > 0 && pw_str.len() <= 255{
+            if !pw_str.is_empty() && pw_str.len() <= 255 {
                 return Some(String::from(pw_str));
             } else {
                 println!("Invalid password length");
@@ -77,55 +115,145 @@ fn parse_expire(m: &ArgMatches) -> u8 {
     2
 }
 
-fn parse_msg(m: &ArgMatches) -> Option<String> {
-    match m.value_of("message") {
-        Some(v) => Some(String::from(v)),
-        None => None,
+fn parse_retry(m: &ArgMatches) -> Result<u8> {
+    match m.value_of("retry") {
+        Some(r) => r.parse().map_err(|_| anyhow!("Invalid retry number {:?}, should be in range 0 to 255", r)),
+        None => Ok(0),
+    }
+}
+
+fn parse_streams(m: &ArgMatches) -> Result<u8> {
+    match m.value_of("streams") {
+        Some(s) => s.parse().map_err(|_| anyhow!("Invalid number of streams {:?}, should be in range 0 to 16", s)),
+        None => Ok(0),
     }
 }
 
+// Heartbeats go every 5 seconds when idle, so a shorter timeout may give up on a live peer.
+fn parse_timeout(m: &ArgMatches) -> Result<Duration> {
+    let secs = match m.value_of("timeout") {
+        Some(t) => t.parse().map_err(|_| anyhow!("Invalid timeout {:?}", t))?,
+        None => 20,
+    };
+    if secs < 10 {
+        return Err(anyhow!("Timeout should be at least 10 seconds"));
+    }
+
+    Ok(Duration::from_secs(secs))
+}
+
+fn parse_limit(m: &ArgMatches) -> Result<Option<u64>> {
+    match m.value_of("limit") {
+        Some(l) => Ok(Some(limit::parse_rate(l)?)),
+        None => Ok(None),
+    }
+}
+
+fn parse_max_files(m: &ArgMatches) -> Result<Option<u64>> {
+    match m.value_of("max-files") {
+        Some(f) => Ok(Some(f.parse().map_err(|_| anyhow!("Invalid number of files {:?}", f))?)),
+        None => Ok(None),
+    }
+}
+
+fn parse_max_size(m: &ArgMatches) -> Result<Option<u64>> {
+    match m.value_of("max-size") {
+        Some(s) => Ok(Some(limit::parse_size(s)?)),
+        None => Ok(None),
+    }
+}
+
+fn parse_interfaces(m: &ArgMatches) -> Vec<String> {
+    match m.values_of("interface") {
+        Some(names) => names.map(String::from).collect(),
+        None => Vec::new(),
+    }
+}
+
+fn parse_mode(m: &ArgMatches) -> ConnectMode {
+    if let Some(addr) = m.value_of("listen") {
+        return ConnectMode::Listen(listen_addr(addr));
+    }
+
+    if let Some(addr) = m.value_of("relay") {
+        // The relay runs on the default port unless given.
+        if addr.contains(':') {
+            return ConnectMode::Relay(String::from(addr));
+        }
+
+        return ConnectMode::Relay(format!("{}:{}", addr, relay::DEFAULT_PORT));
+    }
+
+    match m.value_of("to") {
+        Some(addr) => ConnectMode::To(String::from(addr)),
+        None => ConnectMode::Discover,
+    }
+}
+
+// A port number alone means listening on all interfaces.
+fn listen_addr(addr: &str) -> String {
+    if addr.parse::<u16>().is_ok() {
+        format!("0.0.0.0:{}", addr)
+    } else {
+        String::from(addr)
+    }
+}
+
+fn parse_keep(m: &ArgMatches) -> KeepAttr {
+    KeepAttr {
+        mode: m.occurrences_of("no-mode") == 0,
+        mtime: m.occurrences_of("no-mtime") == 0,
+        atime: m.occurrences_of("no-atime") == 0,
+        owner: m.occurrences_of("owner") > 0,
+    }
+}
+
+fn parse_msg(m: &ArgMatches) -> Option<String> {
+    m.value_of("message").map(String::from)
+}
+
 fn parse_sending_files(m: &ArgMatches) -> Option<Vec<PathBuf>> {
-    match m.values_of("INPUT") {
+    match m.values_of_os("INPUT") {
         Some(mut fs) => parse_files(&mut fs),
         None => None,
     }
 }
 
-fn parse_files(fs: &mut Values) -> Option<Vec<PathBuf>> {
+// Paths may not be UTF-8, so they are read as OS strings.
+fn parse_files(fs: &mut OsValues) -> Option<Vec<PathBuf>> {
     let mut files: Vec<_> = Vec::new();
 
-    while let Some(f) = fs.next() { 
+    for f in fs {
         let path = PathBuf::from(f);
         if path.is_file() || path.is_dir() {
             files.push(path);
         } else {
-            eprintln!("Invalid path: {}", f);
+            eprintln!("Invalid path: {:?}", f);
             std::process::exit(1);
         }
     }
 
-    if files.len() > 0 { Some(files) } else { None }
+    if !files.is_empty() { Some(files) } else { None }
 }
 
-fn parse_code(m: &ArgMatches) -> Result<u16> {
+fn parse_code(m: &ArgMatches) -> Result<String> {
     match m.values_of("INPUT") {
         Some(mut inputs) => parse_code_from_inputs(&mut inputs),
         None => Err(anyhow!("No code input")),
     }
 }
 
-fn parse_code_from_inputs(inputs: &mut Values) -> Result<u16> {
+fn parse_code_from_inputs(inputs: &mut Values) -> Result<String> {
     if inputs.len() != 1 {
         return Err(anyhow!("Only one code input allowed"));
     }
 
     match inputs.next() {
         Some(v) => {
-            match u16::from_str_radix(v, 10) {
-                Ok(port) => Ok(port),
-                Err(_) => Err(anyhow!("Invalid code format")),
+            match code::normalize(v) {
+                Ok(code) => Ok(code),
+                Err(e) => Err(anyhow!("Invalid code format: {}", e)),
             }
-            
         }
         None => Err(anyhow!("Error parsing code from input")),
     }
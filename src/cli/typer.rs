@@ -68,14 +68,14 @@ fn print_fatal(s: &String, line: &LineType) {
 
 fn print_progress(s: &String, line: &mut LineType) {
     check_line_type(line, false);
-    print_flush(&format!("{}", s));
+    print_flush(s);
 
     *line = LineType::Progress;
 }
 
 fn print_prompt(s: &String, tx: &Sender<String>, line: &mut LineType) {
     check_line_type(line, true);
-    print_flush(&format!("{}", s));
+    print_flush(s);
     
     let mut input = String::new();
     if let Err(e) = std::io::stdin().read_line(&mut input) {
@@ -130,8 +130,5 @@ fn check_line_type(line: &LineType, new_line: bool) {
 
 // check whether current line is refreshable.
 fn is_refresh(line: &LineType) -> bool {
-    match line {
-        LineType::Text => false,
-        _ => true,
-    }
+    !matches!(line, LineType::Text)
 }
@@ -0,0 +1,197 @@
+use anyhow::{anyhow, Result};
+use super::meta::Meta;
+
+// A directory tree streamed as one archive, so there's no round trip for each file.
+// The archive is a list of entries, each with 1 byte kind, 4 bytes length and a meta record.
+// A file entry is followed by its content of the size in the meta record.
+// Directories nest like in the file system: entries after a `Dir` are inside it until its `Up`.
+const KIND_DIR: u8 = 1;
+const KIND_FILE: u8 = 2;
+const KIND_UP: u8 = 3;
+
+const HEADER_SIZE: usize = 5;
+
+// Max size of a meta record in the archive, more than any name needs.
+const MAX_META: usize = 0x10000;
+
+#[derive(Debug, PartialEq)]
+pub enum Entry {
+    Dir(Meta),
+    File(Meta),
+    Up,
+}
+
+// What the receiver gets out of the archive bytes.
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    Dir(Meta),
+    File(Meta),
+    Data(Vec<u8>),      // part of the content of the current file.
+    FileEnd,
+    Up,
+}
+
+impl Entry {
+    pub fn encode(&self) -> Vec<u8> {
+        let (kind, meta) = match self {
+            Entry::Dir(m) => (KIND_DIR, m.encode()),
+            Entry::File(m) => (KIND_FILE, m.encode()),
+            Entry::Up => (KIND_UP, Vec::new()),
+        };
+
+        let mut buf = vec![kind];
+        buf.extend_from_slice(&(meta.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&meta);
+
+        buf
+    }
+}
+
+// Parse the archive as it comes, in pieces of any size.
+#[derive(Debug, Default)]
+pub struct Parser {
+    buf: Vec<u8>,
+    remaining: u64,     // content of the current file not parsed yet.
+    depth: usize,       // directories entered and not left yet.
+}
+
+impl Parser {
+    // Parsed bytes are only dropped at the end, as a chunk may hold thousands of small files.
+    pub fn feed(&mut self, data: &[u8]) -> Result<Vec<Event>> {
+        self.buf.extend_from_slice(data);
+        let mut events = Vec::new();
+        let mut pos = 0;
+
+        loop {
+            let rest = &self.buf[pos..];
+            if self.remaining > 0 {
+                if rest.is_empty() {
+                    break;
+                }
+
+                let n = rest.len().min(self.remaining as usize);
+                events.push(Event::Data(rest[..n].to_vec()));
+                pos += n;
+                self.remaining -= n as u64;
+                if self.remaining == 0 {
+                    events.push(Event::FileEnd);
+                }
+
+                continue;
+            }
+
+            if rest.len() < HEADER_SIZE {
+                break;
+            }
+
+            let len = u32::from_be_bytes([rest[1], rest[2], rest[3], rest[4]]) as usize;
+            if len > MAX_META {
+                return Err(anyhow!("Invalid archive entry"));
+            }
+            if rest.len() < HEADER_SIZE + len {
+                break;
+            }
+
+            let kind = rest[0];
+            let meta = rest[HEADER_SIZE..HEADER_SIZE + len].to_vec();
+            pos += HEADER_SIZE + len;
+            let event = self.parse_entry(kind, &meta)?;
+
+            // Empty files have no content, so they end at once.
+            let empty = matches!(event, Event::File(_)) && self.remaining == 0;
+            events.push(event);
+            if empty {
+                events.push(Event::FileEnd);
+            }
+        }
+
+        self.buf.drain(..pos);
+        Ok(events)
+    }
+
+    // All entries parsed and all directories left.
+    pub fn is_complete(&self) -> bool {
+        self.buf.is_empty() && self.remaining == 0 && self.depth == 0
+    }
+
+    fn parse_entry(&mut self, kind: u8, meta: &[u8]) -> Result<Event> {
+        match kind {
+            KIND_DIR => {
+                self.depth += 1;
+                Ok(Event::Dir(Meta::decode(meta)?))
+            },
+            KIND_FILE => {
+                let meta = Meta::decode(meta)?;
+                match meta.size {
+                    Some(size) => {
+                        self.remaining = size;
+                        Ok(Event::File(meta))
+                    },
+                    None => Err(anyhow!("No size for file in archive")),
+                }
+            },
+            KIND_UP if self.depth > 0 => {
+                self.depth -= 1;
+                Ok(Event::Up)
+            },
+            _ => Err(anyhow!("Invalid archive entry")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ffi::OsString;
+
+    fn meta(name: &str, size: Option<u64>) -> Meta {
+        Meta { name: OsString::from(name), size, ..Default::default() }
+    }
+
+    #[test]
+    fn archive_parse_test() {
+        let mut archive = Vec::new();
+        archive.extend(Entry::Dir(meta("src", None)).encode());
+        archive.extend(Entry::File(meta("main.rs", Some(5))).encode());
+        archive.extend(b"hello");
+        archive.extend(Entry::File(meta("empty", Some(0))).encode());
+        archive.extend(Entry::Up.encode());
+
+        // Feed one byte at a time to cross every boundary.
+        let mut parser = Parser::default();
+        let mut events = Vec::new();
+        for b in &archive {
+            events.extend(parser.feed(&[*b]).unwrap());
+        }
+        assert!(parser.is_complete());
+
+        let data: Vec<u8> = events.iter()
+            .filter_map(|e| match e { Event::Data(d) => Some(d.clone()), _ => None })
+            .flatten()
+            .collect();
+        assert_eq!(data, b"hello");
+
+        let events: Vec<Event> = events.into_iter().filter(|e| !matches!(e, Event::Data(_))).collect();
+        assert_eq!(events, vec![
+            Event::Dir(meta("src", None)),
+            Event::File(meta("main.rs", Some(5))),
+            Event::FileEnd,
+            Event::File(meta("empty", Some(0))),
+            Event::FileEnd,
+            Event::Up,
+        ]);
+    }
+
+    #[test]
+    fn archive_invalid_test() {
+        let mut parser = Parser::default();
+        assert!(parser.feed(&Entry::Up.encode()).is_err());
+
+        let mut parser = Parser::default();
+        assert!(parser.feed(&Entry::File(meta("a", None)).encode()).is_err());
+
+        let mut parser = Parser::default();
+        parser.feed(&Entry::Dir(meta("a", None)).encode()).unwrap();
+        assert!(!parser.is_complete());
+    }
+}
@@ -1,39 +1,85 @@
 use super::message::{Message, send_msg, send_prompt};
 use std::path::PathBuf;
+use std::time::Duration;
 
 pub enum Arg {
     S(SendArg),
     R(RecvArg),
+    Relay(RelayArg),
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub enum OverwriteStrategy {
+    #[default]
     Ask,
     Rename,
     Overwrite,
     Skip,
 }
 
+// Which attributes from the sender are applied to the received files.
+// Ownership usually needs root, so it's only kept if asked.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeepAttr {
+    pub mode: bool,
+    pub mtime: bool,
+    pub atime: bool,
+    pub owner: bool,
+}
+
+// How the two sides find each other.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum ConnectMode {
+    #[default]
+    Discover,           // UDP broadcast with the connection code.
+    Listen(String),     // wait on the address for the other side to connect.
+    To(String),         // connect to the other side on the address.
+    Relay(String),      // meet the other side with the connection code on the relay at the address.
+}
+
 #[derive(Debug, Default)]
 pub struct SendArg {
+    pub archive: bool,      // stream each directory as one archive if the receiver supports it.
+    pub compress: bool,     // compress file content if the receiver supports it.
     pub expire: u8,
     pub files: Option<Vec<PathBuf>>,
+    pub interfaces: Vec<String>,    // network interfaces to use, all if empty.
+    pub limit: Option<u64>,     // max rate of file content in bytes per second.
+    pub mode: ConnectMode,
     pub msg: Option<String>,
     pub password: Option<String>,
+    pub retry: u8,      // times to send a file again if its checksum mismatches.
+    pub streams: u8,    // data connections besides the control connection, 0 for none.
+    pub timeout: Duration,  // how long the other side may stay silent before it's taken as gone.
 }
 
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct RecvArg {
-    pub expire: u8,
+    pub expire: u8,     // minutes to wait for the other side to connect.
+    pub daemon: bool,   // keep accepting sessions, each into a subdirectory of `dir`.
     pub dir: PathBuf,
+    pub interfaces: Vec<String>,    // network interfaces to use, all if empty.
+    pub keep: KeepAttr,
+    pub limit: Option<u64>,     // max rate of file content in bytes per second.
+    pub max_files: Option<u64>,     // quota of the session.
+    pub max_size: Option<u64>,
+    pub mode: ConnectMode,
     pub overwrite: OverwriteStrategy,
     pub password: Option<String>,
-    pub code: u16,  // Port number
+    pub resume: bool,   // continue partial files left by an interrupted transmission.
+    pub timeout: Duration,  // how long the other side may stay silent before it's taken as gone.
+    pub yes: bool,      // accept the files without reviewing the manifest.
+    pub code: String,   // Connection code like `7-crossword-banana`, empty if connecting directly.
+}
+
+#[derive(Debug, Default)]
+pub struct RelayArg {
+    pub listen: String,
 }
 
-impl Default for OverwriteStrategy {
+impl Default for KeepAttr {
     fn default() -> Self {
-        OverwriteStrategy::Ask
+        KeepAttr { mode: true, mtime: true, atime: true, owner: false }
     }
 }
 
@@ -49,7 +95,7 @@ impl OverwriteStrategy {
             "r" | "R" => return OverwriteStrategy::Rename,
             "s" | "S" => return OverwriteStrategy::Skip,
             _ => {
-                send_msg(Message::Status(format!("Unknown overwrite strategy chose")));
+                send_msg(Message::Status("Unknown overwrite strategy chose".to_string()));
             }
         }
         
@@ -0,0 +1,117 @@
+use anyhow::{anyhow, Result};
+use rand::Rng;
+use super::wordlist::WORDS;
+
+// Size of the nonce carried in every discovery broadcast.
+pub const NONCE_SIZE: usize = 8;
+
+// Ports in the dynamic range which the connection codes are mapped to.
+const PORT_BASE: u16 = 49152;
+const PORT_RANGE: u16 = 16384;
+
+// Rendezvous parameters derived from a connection code.
+// The sender listens on the port and only accepts broadcasts with the nonce.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rendezvous {
+    pub port: u16,
+    pub nonce: [u8; NONCE_SIZE],
+}
+
+// Generate a random connection code like `7-crossword-banana`.
+pub fn generate() -> String {
+    generate_on(rand::thread_rng().gen_range(1..100))
+}
+
+// Generate a connection code with the given number, like a channel from the relay.
+pub fn generate_on(number: u8) -> String {
+    let mut rng = rand::thread_rng();
+    let first = WORDS[rng.gen_range(0..WORDS.len())];
+    let second = WORDS[rng.gen_range(0..WORDS.len())];
+
+    format!("{}-{}-{}", number, first, second)
+}
+
+// Check the code format and return it in the normalized form.
+// Codes are case insensitive.
+pub fn normalize(code: &str) -> Result<String> {
+    let code = code.trim().to_lowercase();
+    let parts: Vec<&str> = code.split('-').collect();
+    if parts.len() != 3 {
+        return Err(anyhow!("Code should look like `7-crossword-banana`"));
+    }
+
+    match parts[0].parse::<u8>() {
+        Ok(n) if (1..100).contains(&n) => (),
+        _ => return Err(anyhow!("Code should start with a number from 1 to 99")),
+    }
+
+    for word in &parts[1..] {
+        if !WORDS.contains(word) {
+            return Err(anyhow!("Unknown word in code: {}", word));
+        }
+    }
+
+    Ok(code)
+}
+
+// Password for the key exchange when the code is used to meet.
+// Only the words of the code are mixed in, as the number can be seen by others (on the relay,
+// or as the port on the LAN), so anyone in the middle has a single guess at them.
+pub fn pake_password(code: &str, password: Option<&String>) -> String {
+    let words = code.split_once('-').map_or(code, |(_, words)| words);
+    match password {
+        Some(p) => format!("{}\n{}", words, p),
+        None => String::from(words),
+    }
+}
+
+// Map a code to its rendezvous parameters. Both sides get the same result
+// from the same code, so the code is all the receiver needs to find the sender.
+pub fn rendezvous(code: &str) -> Result<Rendezvous> {
+    let code = normalize(code)?;
+    let hash = blake3::derive_key("isend connection code", code.as_bytes());
+
+    let port = PORT_BASE + u16::from_be_bytes([hash[0], hash[1]]) % PORT_RANGE;
+    let mut nonce = [0u8; NONCE_SIZE];
+    nonce.copy_from_slice(&hash[8..8 + NONCE_SIZE]);
+
+    Ok(Rendezvous { port, nonce })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pake_password_test() {
+        let pw = String::from("secret");
+        assert_eq!(pake_password("7-crossword-banana", None), "crossword-banana");
+        assert_ne!(pake_password("7-crossword-banana", Some(&pw)), pake_password("7-crossword-apple", Some(&pw)));
+    }
+
+    #[test]
+    fn generate_code_test() {
+        for _ in 0..100 {
+            let code = generate();
+            assert_eq!(normalize(&code).unwrap(), code);
+        }
+    }
+
+    #[test]
+    fn normalize_code_test() {
+        assert_eq!(normalize(" 7-Crossword-BANANA ").unwrap(), "7-crossword-banana");
+        assert!(normalize("7-crossword").is_err());
+        assert!(normalize("0-crossword-banana").is_err());
+        assert!(normalize("100-crossword-banana").is_err());
+        assert!(normalize("7-crossword-bananas").is_err());
+        assert!(normalize("38060").is_err());
+    }
+
+    #[test]
+    fn rendezvous_test() {
+        let r = rendezvous("7-crossword-banana").unwrap();
+        assert_eq!(r, rendezvous("7-CROSSWORD-banana").unwrap());
+        assert!(r.port >= PORT_BASE);
+        assert_ne!(r, rendezvous("8-crossword-banana").unwrap());
+    }
+}
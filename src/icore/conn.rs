@@ -0,0 +1,389 @@
+use anyhow::{anyhow, Result};
+use async_std::net::TcpStream;
+use async_std::prelude::*;
+use async_std::sync::Mutex;
+use async_std::task;
+use futures::FutureExt;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Max size of one encrypted frame, large enough for any instruction with its content.
+const MAX_FRAME: usize = 0x4000000;
+// Send a heartbeat after this long without sending anything else.
+const HEARTBEAT: Duration = Duration::from_secs(5);
+
+// A TCP connection that transfers bytes either in plain text or
+// in authenticated encrypted frames once the session keys are set.
+pub struct Conn {
+    stream: TcpStream,
+    writer: Arc<Mutex<Writer>>,     // shared with the heartbeat task.
+    recv: Option<Cipher>,
+    buf: Vec<u8>,       // decrypted bytes not read yet.
+    inbox: Vec<u8>,     // bytes taken from the stream while waiting to send, not decrypted yet.
+    secret: Vec<u8>,    // shared secret of the session, empty before encryption.
+    timeout: Option<Duration>,      // how long the other side may stay silent.
+    lost: bool,         // a read or write failed, nothing more can go through.
+    pub caps: u32,      // capabilities both sides agreed on in the handshake.
+}
+
+struct Writer {
+    stream: TcpStream,
+    send: Option<Cipher>,
+    last: Instant,      // when something was sent last time.
+}
+
+// One key for each direction so the nonce counters never collide.
+struct Cipher {
+    key: ChaCha20Poly1305,
+    count: u64,
+}
+
+impl Conn {
+    // Each write is a whole frame, so there's no need to wait for more bytes to fill a packet.
+    pub fn new(stream: TcpStream) -> Self {
+        if let Err(e) = stream.set_nodelay(true) {
+            log::debug!("Cannot set TCP no delay: {}", e);
+        }
+
+        let writer = Writer { stream: stream.clone(), send: None, last: Instant::now() };
+        Conn {
+            stream,
+            writer: Arc::new(Mutex::new(writer)),
+            recv: None,
+            buf: Vec::new(),
+            inbox: Vec::new(),
+            secret: Vec::new(),
+            timeout: None,
+            lost: false,
+            caps: 0,
+        }
+    }
+
+    // Derive the session keys from the shared secret and switch to encrypted frames.
+    // `initiator` is the side which sent the Connect request.
+    pub fn encrypt(&mut self, secret: &[u8], initiator: bool) -> Result<()> {
+        let hk = Hkdf::<Sha256>::new(Some(b"isend session"), secret);
+        let mut to_recv = [0u8; 32];
+        let mut to_send = [0u8; 32];
+        hk.expand(b"initiator to responder", &mut to_recv)
+            .map_err(|_| anyhow!("Cannot derive session key"))?;
+        hk.expand(b"responder to initiator", &mut to_send)
+            .map_err(|_| anyhow!("Cannot derive session key"))?;
+
+        let (send_key, recv_key) = if initiator { (to_recv, to_send) } else { (to_send, to_recv) };
+        // Nobody else holds the writer before the heartbeat starts.
+        let writer = Arc::get_mut(&mut self.writer)
+            .ok_or_else(|| anyhow!("Cannot set session keys after the heartbeat started"))?;
+        writer.get_mut().send = Some(Cipher::new(&send_key));
+        self.recv = Some(Cipher::new(&recv_key));
+        self.secret = secret.to_vec();
+
+        Ok(())
+    }
+
+    // Give up reading or writing once nothing comes from the other side for `timeout`.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+
+    // Send `beat` from a task of its own whenever nothing else was sent for a while,
+    // so the other side knows this one is still there while it's busy with a prompt or the disk.
+    // The task ends with the connection.
+    pub fn keep_alive(&self, beat: Vec<u8>) {
+        let writer = Arc::downgrade(&self.writer);
+        task::spawn(async move {
+            loop {
+                task::sleep(Duration::from_secs(1)).await;
+                let shared = match writer.upgrade() {
+                    Some(w) => w,
+                    None => break,
+                };
+                // Something else is being sent already.
+                let mut writer = match shared.try_lock() {
+                    Some(w) => w,
+                    None => continue,
+                };
+                if writer.last.elapsed() < HEARTBEAT {
+                    continue;
+                }
+                if let Err(e) = writer.send(&beat).await {
+                    log::debug!("Heartbeat stopped: {}", e);
+                    break;
+                }
+            }
+        });
+    }
+
+    // Secret of a data connection of this session, so each one has its own keys.
+    pub fn stream_secret(&self, index: u8) -> Result<[u8; 32]> {
+        if self.secret.is_empty() {
+            return Err(anyhow!("Connection not encrypted"));
+        }
+
+        let hk = Hkdf::<Sha256>::new(Some(b"isend stream"), &self.secret);
+        let mut secret = [0u8; 32];
+        hk.expand(&[index], &mut secret).map_err(|_| anyhow!("Cannot derive stream key"))?;
+
+        Ok(secret)
+    }
+
+    // Whether a read or write failed before, like when the other side is gone.
+    pub fn is_lost(&self) -> bool {
+        self.lost
+    }
+
+    // Write all bytes. In encrypted mode they are sent as a single frame:
+    // 4 bytes length followed by the ciphertext with its tag.
+    pub async fn write_all(&mut self, bytes: &[u8]) -> Result<()> {
+        let shared = self.writer.clone();
+        let result = match while_alive(&self.stream, &mut self.inbox, self.timeout, shared.lock()).await {
+            Ok(mut writer) => while_alive(&self.stream, &mut self.inbox, self.timeout, writer.send(bytes)).await
+                .and_then(|sent| sent),
+            Err(e) => Err(e),
+        };
+        self.lost |= result.is_err();
+
+        result
+    }
+
+    // Wait for `op` as long as the other side is still there.
+    pub async fn alive<T>(&mut self, op: impl Future<Output = T>) -> Result<T> {
+        let result = while_alive(&self.stream, &mut self.inbox, self.timeout, op).await;
+        self.lost |= result.is_err();
+
+        result
+    }
+
+    // Read exactly `length` bytes, decrypting as many frames as needed.
+    pub async fn read_exact(&mut self, length: usize) -> Result<Vec<u8>> {
+        let result = self.read_buffered(length).await;
+        self.lost |= result.is_err();
+
+        result
+    }
+
+    async fn read_buffered(&mut self, length: usize) -> Result<Vec<u8>> {
+        if self.recv.is_none() {
+            return self.read_plain(length).await;
+        }
+
+        while self.buf.len() < length {
+            let frame = self.read_frame().await?;
+            self.buf.extend_from_slice(&frame);
+        }
+
+        let rest = self.buf.split_off(length);
+        Ok(std::mem::replace(&mut self.buf, rest))
+    }
+
+    // Read and decrypt one frame from the stream.
+    async fn read_frame(&mut self) -> Result<Vec<u8>> {
+        let len_buf = self.read_plain(4).await?;
+        let len = u32::from_be_bytes([len_buf[0], len_buf[1], len_buf[2], len_buf[3]]) as usize;
+        if len > MAX_FRAME {
+            return Err(anyhow!("Encrypted frame too large"));
+        }
+
+        let frame = self.read_plain(len).await?;
+        let c = self.recv.as_mut().ok_or_else(|| anyhow!("Connection not encrypted"))?;
+        let plain = c.key.decrypt(Nonce::from_slice(&to_nonce(c.count)), frame.as_ref())
+            .map_err(|_| anyhow!("Cannot decrypt frame, data may be tampered"))?;
+        c.count += 1;
+
+        Ok(plain)
+    }
+
+    // Read exactly `length` bytes from the inbox first, then from the stream as they come.
+    async fn read_plain(&mut self, length: usize) -> Result<Vec<u8>> {
+        let rest = self.inbox.split_off(length.min(self.inbox.len()));
+        let mut buf = std::mem::replace(&mut self.inbox, rest);
+        let mut filled = buf.len();
+        buf.resize(length, 0);
+
+        while filled < length {
+            let reading = self.stream.read(&mut buf[filled..]);
+            let n = match self.timeout {
+                Some(t) => async_std::future::timeout(t, reading).await.map_err(|_| silent(t))??,
+                None => reading.await?,
+            };
+            if n == 0 {
+                return Err(anyhow!("Connection closed by peer"));
+            }
+            filled += n;
+        }
+
+        Ok(buf)
+    }
+
+    // Whether something came from the other side, without waiting or reading it.
+    // The end of the connection counts too, so reading it finds out.
+    pub fn has_incoming(&self) -> bool {
+        let mut byte = [0u8; 1];
+        !self.buf.is_empty() || !self.inbox.is_empty()
+            || matches!(self.stream.peek(&mut byte).now_or_never(), Some(Ok(_)))
+    }
+
+    // Read and drop everything until the other side closes the connection.
+    // After an abort, so the other side can still read it instead of getting a reset.
+    pub async fn discard(&mut self) {
+        self.inbox.clear();
+        let mut buf = vec![0u8; 0x10000];
+        while let Ok(n) = self.stream.read(&mut buf).await {
+            if n == 0 {
+                break;
+            }
+        }
+    }
+}
+
+impl Writer {
+    async fn send(&mut self, bytes: &[u8]) -> Result<()> {
+        match &mut self.send {
+            Some(c) => {
+                let frame = c.key.encrypt(Nonce::from_slice(&to_nonce(c.count)), bytes)
+                    .map_err(|_| anyhow!("Cannot encrypt frame"))?;
+                c.count += 1;
+
+                let mut buf = Vec::with_capacity(frame.len() + 4);
+                buf.extend_from_slice(&u32::to_be_bytes(frame.len() as u32));
+                buf.extend_from_slice(&frame);
+                self.stream.write_all(&buf).await?;
+            },
+            None => self.stream.write_all(bytes).await?,
+        }
+        self.last = Instant::now();
+
+        Ok(())
+    }
+}
+
+impl Cipher {
+    fn new(key: &[u8]) -> Self {
+        Cipher { key: ChaCha20Poly1305::new(Key::from_slice(key)), count: 0 }
+    }
+}
+
+// Wait for `op`, which may take long if the other side doesn't read for a while,
+// like when its user answers a prompt. It still sends heartbeats meanwhile,
+// so only give up if nothing at all comes from it for `timeout`.
+async fn while_alive<T>(stream: &TcpStream, inbox: &mut Vec<u8>, timeout: Option<Duration>,
+    op: impl Future<Output = T>) -> Result<T> {
+    let t = match timeout {
+        Some(t) => t,
+        None => return Ok(op.await),
+    };
+
+    futures::pin_mut!(op);
+    loop {
+        match async_std::future::timeout(t, op.as_mut()).await {
+            Ok(out) => return Ok(out),
+            Err(_) if pull(stream, inbox) => continue,
+            Err(_) => return Err(silent(t)),
+        }
+    }
+}
+
+// Move what already came from the other side to the inbox without waiting.
+// Return whether there was anything.
+fn pull(mut stream: &TcpStream, inbox: &mut Vec<u8>) -> bool {
+    let mut buf = vec![0u8; 0x10000];
+    let mut pulled = false;
+    while let Some(Ok(n)) = stream.read(&mut buf).now_or_never() {
+        if n == 0 {
+            break;
+        }
+        inbox.extend_from_slice(&buf[..n]);
+        pulled = true;
+    }
+
+    pulled
+}
+
+fn silent(timeout: Duration) -> anyhow::Error {
+    anyhow!("Nothing from the other side in {} seconds, connection lost", timeout.as_secs())
+}
+
+// 96 bits nonce built from the frame counter.
+fn to_nonce(count: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&count.to_be_bytes());
+
+    nonce
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_std::net::TcpListener;
+
+    async fn pair() -> (Conn, Conn) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = TcpStream::connect(addr).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        (Conn::new(client), Conn::new(server))
+    }
+
+    #[async_std::test]
+    async fn encrypted_roundtrip_test() {
+        let (mut a, mut b) = pair().await;
+        a.encrypt(b"shared secret", true).unwrap();
+        b.encrypt(b"shared secret", false).unwrap();
+
+        a.write_all(b"hello").await.unwrap();
+        a.write_all(b" world").await.unwrap();
+        assert_eq!(b.read_exact(8).await.unwrap(), b"hello wo".to_vec());
+        assert_eq!(b.read_exact(3).await.unwrap(), b"rld".to_vec());
+
+        b.write_all(b"reply").await.unwrap();
+        assert_eq!(a.read_exact(5).await.unwrap(), b"reply".to_vec());
+    }
+
+    #[async_std::test]
+    async fn has_incoming_test() {
+        let (mut a, mut b) = pair().await;
+        assert!(!b.has_incoming());
+
+        a.write_all(b"abort").await.unwrap();
+        async_std::task::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(b.has_incoming());
+        assert_eq!(b.read_exact(5).await.unwrap(), b"abort".to_vec());
+        assert!(!b.has_incoming());
+    }
+
+    #[async_std::test]
+    async fn timeout_test() {
+        let (mut a, mut b) = pair().await;
+        b.set_timeout(Duration::from_millis(200));
+        assert!(b.read_exact(5).await.is_err());
+
+        // Waiting goes on while the other side sends something, which is kept for reading.
+        let beats = task::spawn(async move {
+            for _ in 0..5 {
+                a.write_all(b"x").await.unwrap();
+                task::sleep(Duration::from_millis(100)).await;
+            }
+            a
+        });
+        assert!(b.alive(task::sleep(Duration::from_millis(600))).await.is_ok());
+        assert_eq!(b.read_exact(5).await.unwrap(), b"xxxxx".to_vec());
+
+        let _a = beats.await;
+        assert!(b.alive(futures::future::pending::<()>()).await.is_err());
+    }
+
+    #[async_std::test]
+    async fn wrong_key_test() {
+        let (mut a, mut b) = pair().await;
+        a.encrypt(b"shared secret", true).unwrap();
+        b.encrypt(b"other secret", false).unwrap();
+
+        a.write_all(b"hello").await.unwrap();
+        assert!(b.read_exact(5).await.is_err());
+    }
+}
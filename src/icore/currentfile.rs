@@ -1,30 +1,41 @@
 use anyhow::{anyhow, Result};
 use async_std::fs::File;
-use std::path::PathBuf;
+use async_std::prelude::*;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use super::meta::{self, FileAttr, Meta};
+use super::stats::Meter;
 
 // Used to record the current transmitting file.
 #[derive(Debug, Default)]
 pub struct CurrentFile {
     pub fd: Option<File>,     // used only in receiver as file descriptor.
     pub path: PathBuf,
-    pub name: String,       // path may vary on different side.
+    pub part: Option<PathBuf>,  // receiver writes to it and renames it to path when verified.
+    pub name: OsString,     // path may vary on different side.
     pub size: u64,          // name and size are meta info to send and receive.
     pub transmitted: u64,   // the size that has been transmitted.
+    pub offset: u64,        // the position a resumed transmission started from.
+    pub hasher: blake3::Hasher,     // checksum of the content on both sides.
+    pub attr: FileAttr,     // sent with the meta info and applied after the file is received.
+    pub id: u16,            // id of the start request, which ranges from data connections refer to.
+    pub meter: Option<Meter>,   // rate of the content since it started.
 }
 
 impl CurrentFile {
     // used in sender side. Init an object with pathbuf.
-    pub fn from(path: &PathBuf) -> Result<Self> {
-        let name = match read_file_name(path) {
-            Some(f) => f,
+    pub fn from(path: &Path) -> Result<Self> {
+        let name = match path.file_name() {
+            Some(f) => f.to_os_string(),
             None => return Err(anyhow!("Cannot read file name")),
         };
 
-        let size = std::fs::metadata(path)?.len();
+        let metadata = std::fs::metadata(path)?;
         let current = CurrentFile {
-            path: path.clone(),
+            path: path.to_path_buf(),
             name,
-            size,
+            size: metadata.len(),
+            attr: FileAttr::from(&metadata),
             ..Default::default()
         };
         log::debug!("Init current file in sender: {:?}", current);
@@ -41,21 +52,47 @@ impl CurrentFile {
         }
     }
 
-    pub fn meta_to_string(&self) -> String {
-        format!("size:{};name:{}", self.size, self.name)
+    // Where the content is written, the part until the file is verified.
+    pub fn write_path(&self) -> &Path {
+        self.part.as_deref().unwrap_or(&self.path)
     }
 
-    // Set the name and size field according to the meta string. used in receiver.
-    pub fn meta_from_string(meta: &String) -> Result<(u64, String)> {
-        let metas: Vec<&str> = meta.split(|c| c == ':' || c == ';').collect();
-        if metas.len() != 4 {
-            return Err(anyhow!("Invalid meta string format"));
+    // Meta record to send before the content.
+    pub fn to_meta(&self) -> Meta {
+        Meta { size: Some(self.size), name: self.name.clone(), attr: self.attr.clone() }
+    }
+
+    // Feed the first `len` bytes of the local file into the hasher.
+    // Used in resuming as the content before the offset is not transmitted again.
+    pub async fn hash_existing(&mut self, len: u64) -> Result<()> {
+        let file = File::open(self.write_path()).await?;
+        let mut reader = file.take(len);
+        let mut buf = vec![0u8; 0x10000];
+
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 { break; }
+            self.hasher.update(&buf[..n]);
         }
 
-        let size = metas[1].parse()?;
-        let name = String::from(metas[3]);
+        Ok(())
+    }
+
+    // Hex string of the checksum of the content hashed so far.
+    pub fn digest(&self) -> String {
+        self.hasher.finalize().to_hex().to_string()
+    }
+
+    // Start measuring the rate, from the offset of a resumed file.
+    pub fn start(&mut self) {
+        self.meter = Some(Meter::new(self.transmitted));
+    }
 
-        Ok((size, name))
+    pub fn advance(&mut self, n: u64) {
+        self.transmitted += n;
+        if let Some(meter) = self.meter.as_mut() {
+            meter.update(self.transmitted);
+        }
     }
 
     // Get the current progress of transmission with certain format.
@@ -63,19 +100,23 @@ impl CurrentFile {
         let total = human_read_size(self.size);
         let transmitted = human_read_size(self.transmitted);
 
-        format!("File: \"{}\"\t\tProgress: {}/{}", self.name, transmitted, total)
-    }
-}
+        let resumed = if self.offset > 0 {
+            format!(" (resumed at {})", human_read_size(self.offset))
+        } else {
+            String::new()
+        };
 
-// Helper function to read file name.
-fn read_file_name(file: &PathBuf) -> Option<String> {
-    let filename = file.file_name()?.to_str()?;
+        let rate = match &self.meter {
+            Some(m) => format!(", {}", m.describe(Some(self.size))),
+            None => String::new(),
+        };
 
-    Some(String::from(filename))
+        format!("File: \"{}\"\t\tProgress: {}/{}{}{}", meta::display_name(&self.name), transmitted, total, resumed, rate)
+    }
 }
 
 // Convert the size number to a human readable string.
-fn human_read_size(size: u64) -> String {
+pub fn human_read_size(size: u64) -> String {
     let suffix = ["B", "KB", "MB", "GB", "TB"];
     let mut result = format!("{}B", size);
 
@@ -96,24 +137,20 @@ mod test {
 
     #[test]
     fn meta_read_get_test() {
-        let mut f = CurrentFile{
-            name: String::from("Hello"),
+        let f = CurrentFile{
+            name: OsString::from("notes:v2;final.txt"),
             size: 2954040,
             ..Default::default()
         };
 
-        if let Ok((size, name)) = CurrentFile::meta_from_string(&f.meta_to_string()) {
-            f.name = name;
-            f.size = size;
-        }
-
-        assert_eq!(f.name, String::from("Hello"));
-        assert_eq!(f.size, 2954040);
+        let meta = Meta::decode(&f.to_meta().encode()).unwrap();
+        assert_eq!(meta.name, OsString::from("notes:v2;final.txt"));
+        assert_eq!(meta.size, Some(2954040));
     }
 
     #[test]
     fn human_read_size_test() {
         let size = 10240241u64;
-        assert_eq!(human_read_size(size), String::from("9.77MB"));
+        assert_eq!(human_read_size(size), String::from("9.8MB"));
     }
 }
\ No newline at end of file
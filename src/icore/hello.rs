@@ -0,0 +1,133 @@
+use anyhow::{anyhow, Result};
+
+// Sent by both sides in the Connect handshake, before the PAKE message,
+// so peers with different wire formats find out before anything else is sent.
+// Layout: 4 bytes magic, 2 bytes protocol version, 4 bytes capability bits.
+pub const HELLO_SIZE: usize = 10;
+
+const MAGIC: &[u8; 4] = b"ISND";
+
+// Version of the wire format. Bump it when an existing message changes,
+// and keep the oldest version this build still speaks in `MIN_VERSION`.
+pub const PROTOCOL_VERSION: u16 = 1;
+const MIN_VERSION: u16 = 1;
+
+// Capabilities. Optional ones are only used if both sides have them.
+pub const CAP_ENCRYPTION: u32 = 1;
+pub const CAP_CHECKSUM: u32 = 1 << 1;
+pub const CAP_METADATA: u32 = 1 << 2;
+pub const CAP_RESUME: u32 = 1 << 3;
+pub const CAP_COMPRESSION: u32 = 1 << 4;
+pub const CAP_ARCHIVE: u32 = 1 << 5;
+pub const CAP_STREAMS: u32 = 1 << 6;
+pub const CAP_MANIFEST: u32 = 1 << 7;
+pub const CAP_ABORT: u32 = 1 << 8;
+pub const CAP_HEARTBEAT: u32 = 1 << 9;
+
+// Capabilities this build cannot work without.
+const REQUIRED: u32 = CAP_ENCRYPTION | CAP_CHECKSUM | CAP_METADATA;
+
+const CAP_NAMES: [(u32, &str); 10] = [
+    (CAP_ENCRYPTION, "encryption"),
+    (CAP_CHECKSUM, "checksums"),
+    (CAP_METADATA, "metadata"),
+    (CAP_RESUME, "resume"),
+    (CAP_COMPRESSION, "compression"),
+    (CAP_ARCHIVE, "archive"),
+    (CAP_STREAMS, "multiple streams"),
+    (CAP_MANIFEST, "manifest"),
+    (CAP_ABORT, "abort"),
+    (CAP_HEARTBEAT, "heartbeat"),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Hello {
+    pub version: u16,
+    pub caps: u32,
+}
+
+impl Hello {
+    // What this build supports.
+    pub fn local() -> Self {
+        Hello {
+            version: PROTOCOL_VERSION,
+            caps: CAP_ENCRYPTION | CAP_CHECKSUM | CAP_METADATA | CAP_RESUME | CAP_COMPRESSION | CAP_ARCHIVE
+                | CAP_STREAMS | CAP_MANIFEST | CAP_ABORT | CAP_HEARTBEAT,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; HELLO_SIZE] {
+        let mut buf = [0u8; HELLO_SIZE];
+        buf[..4].copy_from_slice(MAGIC);
+        buf[4..6].copy_from_slice(&self.version.to_be_bytes());
+        buf[6..].copy_from_slice(&self.caps.to_be_bytes());
+
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() < HELLO_SIZE || &buf[..4] != MAGIC {
+            return Err(anyhow!("The other side uses an older isend without version check, please upgrade it"));
+        }
+
+        let version = u16::from_be_bytes([buf[4], buf[5]]);
+        let caps = u32::from_be_bytes([buf[6], buf[7], buf[8], buf[9]]);
+
+        Ok(Hello { version, caps })
+    }
+
+    // Agree on the highest version both sides speak and the capabilities both have.
+    // The error is the reason to refuse the other side.
+    pub fn negotiate(&self, remote: &Hello) -> Result<Hello> {
+        let version = self.version.min(remote.version);
+        if version < MIN_VERSION {
+            return Err(anyhow!("Protocol version {} is too old, at least {} is needed", remote.version, MIN_VERSION));
+        }
+
+        let missing = REQUIRED & !remote.caps;
+        if missing != 0 {
+            return Err(anyhow!("The other side doesn't support {}", cap_names(missing)));
+        }
+
+        Ok(Hello { version, caps: self.caps & remote.caps })
+    }
+}
+
+fn cap_names(caps: u32) -> String {
+    let names: Vec<&str> = CAP_NAMES.iter()
+        .filter(|(cap, _)| caps & cap != 0)
+        .map(|(_, name)| *name)
+        .collect();
+
+    names.join(", ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hello_roundtrip_test() {
+        let hello = Hello::local();
+        assert_eq!(Hello::decode(&hello.encode()).unwrap(), hello);
+        assert!(Hello::decode(&[0u8; 32]).is_err());
+        assert!(Hello::decode(b"ISND").is_err());
+    }
+
+    #[test]
+    fn negotiate_test() {
+        let local = Hello::local();
+        let newer = Hello { version: PROTOCOL_VERSION + 1, caps: local.caps | 1 << 20 };
+        assert_eq!(local.negotiate(&newer).unwrap(), local);
+
+        let no_resume = Hello { version: PROTOCOL_VERSION, caps: REQUIRED };
+        assert_eq!(local.negotiate(&no_resume).unwrap().caps, REQUIRED);
+
+        let plain = Hello { version: PROTOCOL_VERSION, caps: CAP_CHECKSUM };
+        let e = local.negotiate(&plain).unwrap_err().to_string();
+        assert!(e.contains("encryption, metadata"));
+
+        let old = Hello { version: 0, caps: local.caps };
+        assert!(local.negotiate(&old).is_err());
+    }
+}
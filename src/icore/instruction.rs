@@ -1,23 +1,39 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use num_enum::TryFromPrimitive;
 use std::convert::TryFrom;
 use super::message::{Message, send_msg};
 
 pub const INS_SIZE: usize = 8;
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq, TryFromPrimitive)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, TryFromPrimitive)]
 #[repr(u8)]
 pub enum Operation {
     // Request operation code.
-    Connect = 10,           // with or without password, needs reply
+    #[default]
+    Connect = 10,           // with PAKE message, needs reply with PAKE message and confirmation
+    KeyConfirm = 11,        // with PAKE confirmation, needs reply
+    Register = 12,          // with role and key to the relay, replied when paired
+    OpenStreams = 13,       // with the number of data connections, needs reply
+    JoinStream = 14,        // with the index of the data connection and its token
+    SendManifest = 15,      // with the totals of the files to send, needs reply
+    OpenChannel = 18,       // to the relay, replied with a free channel, then a JoinChannel for each receiver
+    JoinChannel = 19,       // with the channel to the relay, replied when paired; or with a token to the sender
     StartSendFile = 20,     // with file name, needs reply
     SendFileContent = 21,   // with file content
-    EndSendFile = 22,       // needs reply
+    EndSendFile = 22,       // with content checksum, needs reply
+    SendFileRange = 23,     // with offset and file content, on a data connection
+    AbortFile = 24,         // with the reason, the current file is given up
     StartSendDir = 30,      // with dir name
     EndSendDir = 31,        // needs reply
+    AbortDir = 32,          // with the reason, the current directory or archive is given up
     SendMsg = 40,           // with message length
+    StartArchive = 50,      // with root dir meta, needs reply
+    ArchiveData = 51,       // with a piece of the archive stream
+    EndArchive = 52,        // with archive checksum, needs reply
 
     Disconnect = 100,          // needs reply
+    AbortSession = 101,     // with the reason, from either side, the session ends at once
+    Heartbeat = 102,        // sent when idle for a while, so the other side knows this one is still there
 
     // Response operation code.
     RequestSuccess = 200,   
@@ -25,17 +41,12 @@ pub enum Operation {
     RequestError = 202,         // with reply content, needs to retry
 }
 
-impl Default for Operation {
-    fn default() -> Self {
-        Operation::Connect
-    }
-}
-
 #[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct Instruction {
     pub id: u16,
     pub operation: Operation,
     pub buffer: bool,
+    pub compressed: bool,   // content compressed with LZ4, only if both sides agreed.
     pub length: u32,    // max 16M for one frame
 }
 
@@ -48,36 +59,33 @@ impl Instruction {
         buf[1] = u16::to_be_bytes(self.id)[1];
         // Position 3 is the operation code.
         buf[2] = (self.operation as u8).to_be_bytes()[0];
-        // Position 4 is a boolean representing with buffer or not
-        buf[3] = if self.buffer {1} else {0};
+        // Position 4 is the flags: bit 0 for with buffer or not, bit 1 for compressed content.
+        buf[3] = u8::from(self.buffer) | u8::from(self.compressed) << 1;
         // Position 5~8 is the length of the following content
-        let len_bytes = u32::to_be_bytes(self.length);
-        let mut i = 4;
-        for byte in len_bytes.iter() {
-            buf[i] = *byte;
-            i += 1;
-        }
+        buf[4..].copy_from_slice(&u32::to_be_bytes(self.length));
 
         buf
     }
 
     // Decode a vector of u8 to an instruction
-    pub fn decode(buf: &Vec<u8>) -> Result<Self> {
+    pub fn decode(buf: &[u8]) -> Result<Self> {
         if buf.len() != INS_SIZE {
-            send_msg(Message::Fatal(format!("Unknown instruction format")));
+            send_msg(Message::Fatal("Unknown instruction format".to_string()));
         }
 
         let id = u16::from_be_bytes([buf[0], buf[1]]);
 
         let operation_num = u8::from_be_bytes([buf[2]]);
-        let operation = Operation::try_from(operation_num)?;
+        let operation = Operation::try_from(operation_num)
+            .map_err(|_| anyhow!("Unknown operation code {}, the other side may use a newer protocol", operation_num))?;
 
-        let buffer_num = u8::from_be_bytes([buf[3]]);
-        let buffer = if buffer_num == 1 {true} else {false};
+        let flags = u8::from_be_bytes([buf[3]]);
+        let buffer = flags & 1 != 0;
+        let compressed = flags & 2 != 0;
 
         let length = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
 
-        Ok(Instruction{ id, operation, buffer, length })
+        Ok(Instruction{ id, operation, buffer, compressed, length })
     }
 }
 
@@ -87,19 +95,25 @@ mod test {
 
     #[test]
     fn encode_ins_test() {
-        let ins = Instruction {id: 5, operation: Operation::Connect, buffer: true, length: 43375};
+        let mut ins = Instruction {id: 5, operation: Operation::Connect, buffer: true, compressed: false, length: 43375};
         let mut arr: [u8; 8] = [0, 5, 10, 1, 0, 0, 169, 111];
         assert_eq!(ins.encode(), arr);
         arr[7] += 1;
         assert_ne!(ins.encode(), arr);
+
+        ins.compressed = true;
+        assert_eq!(ins.encode()[3], 3);
     }
 
     #[test]
     fn decode_ins_test() {
-        let ins = Instruction {id: 5, operation: Operation::Connect, buffer: true, length: 43375};
+        let ins = Instruction {id: 5, operation: Operation::Connect, buffer: true, compressed: false, length: 43375};
         let mut vec = vec![0, 5, 10, 1, 0, 0, 169, 111];
         assert_eq!(Instruction::decode(&vec).unwrap(), ins);
         vec[0] += 1;
         assert_ne!(Instruction::decode(&vec).unwrap(), ins);
+
+        vec[3] = 3;
+        assert!(Instruction::decode(&vec).unwrap().compressed);
     }
 }
\ No newline at end of file
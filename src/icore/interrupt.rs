@@ -0,0 +1,59 @@
+use anyhow::{anyhow, Result};
+use futures::future::{self, Either};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+// Set by the first Ctrl-C. The sender and receiver check it between chunks and requests,
+// so they can tell the other side and clean up before they stop.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Catch Ctrl-C for the rest of the process. The second one quits at once,
+// in case the first is stuck, like waiting for the user to answer a prompt.
+// The handler doesn't go through the message pipe, which is locked while waiting for input.
+pub fn watch() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            eprintln!("\nProcess exit");
+            std::process::exit(130);
+        }
+        eprintln!("\nInterrupted, aborting the session. Press Ctrl-C again to quit now");
+    })?;
+
+    Ok(())
+}
+
+pub fn is_set() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+// The error to stop with once interrupted.
+pub fn check() -> Result<()> {
+    match is_set() {
+        true => Err(interrupted()),
+        false => Ok(()),
+    }
+}
+
+fn interrupted() -> anyhow::Error {
+    anyhow!("Interrupted by the user")
+}
+
+// Wait until interrupted.
+async fn wait() {
+    while !is_set() {
+        async_std::task::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+// Stop waiting for `reading` if interrupted. The read is dropped half way,
+// so nothing but an abort should go on the connection after that.
+// Check first, as a read which is ready wins the race every time while content keeps coming.
+pub async fn or_interrupt<T>(reading: impl Future<Output = Result<T>>) -> Result<T> {
+    check()?;
+    futures::pin_mut!(reading);
+    match future::select(reading, Box::pin(wait())).await {
+        Either::Left((result, _)) => result,
+        Either::Right(_) => Err(interrupted()),
+    }
+}
@@ -0,0 +1,162 @@
+use anyhow::{anyhow, Result};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use super::currentfile::human_read_size;
+
+// Limit the rate of file content with a token bucket.
+// Clones share the bucket, so all files and data connections of a session count together.
+#[derive(Clone, Debug, Default)]
+pub struct Limiter {
+    bucket: Option<Arc<Mutex<Bucket>>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,      // bytes per second.
+    tokens: f64,    // negative when a chunk was larger than the tokens left.
+    last: Instant,
+}
+
+impl Limiter {
+    // No limit if `rate` is None.
+    pub fn new(rate: Option<u64>) -> Self {
+        let bucket = rate.map(|r| {
+            let rate = r as f64;
+            Arc::new(Mutex::new(Bucket { rate, tokens: rate, last: Instant::now() }))
+        });
+
+        Limiter { bucket }
+    }
+
+    // Smaller chunks with a low limit keep the rate smooth, about 1/8 second each.
+    pub fn chunk_size(&self, max: usize) -> usize {
+        match &self.bucket {
+            Some(b) => ((b.lock().unwrap().rate / 8.0) as usize).clamp(0x1000, max),
+            None => max,
+        }
+    }
+
+    // Wait until `n` bytes are allowed to go.
+    pub async fn take(&self, n: usize) {
+        let wait = match &self.bucket {
+            Some(b) => b.lock().unwrap().reserve(n as f64),
+            None => return,
+        };
+
+        if !wait.is_zero() {
+            async_std::task::sleep(wait).await;
+        }
+    }
+}
+
+// Max files and bytes the receiver takes in a session, no limit if None.
+#[derive(Debug, Default)]
+pub struct Quota {
+    max_files: Option<u64>,
+    max_size: Option<u64>,
+    files: u64,     // taken so far.
+    bytes: u64,
+}
+
+impl Bucket {
+    // Take the tokens at once and return how long to wait until they are paid back.
+    // Up to one second of tokens are saved while idle, so the rate is never above the limit for long.
+    fn reserve(&mut self, n: f64) -> Duration {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate) - n;
+        self.last = now;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+impl Quota {
+    pub fn new(max_files: Option<u64>, max_size: Option<u64>) -> Self {
+        Quota { max_files, max_size, ..Default::default() }
+    }
+
+    // Whether `files` more files of `bytes` in total are within the quota, without taking them.
+    pub fn check(&self, files: u64, bytes: u64) -> Result<()> {
+        match (self.max_files, self.max_size) {
+            (Some(max), _) if self.files + files > max => Err(anyhow!("Over the receiver's quota of {} files", max)),
+            (_, Some(max)) if self.bytes + bytes > max => {
+                Err(anyhow!("Over the receiver's quota of {}", human_read_size(max)))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    pub fn take(&mut self, files: u64, bytes: u64) -> Result<()> {
+        self.check(files, bytes)?;
+        self.files += files;
+        self.bytes += bytes;
+
+        Ok(())
+    }
+}
+
+// Parse a rate in bytes per second like `20M` or `512KB`.
+pub fn parse_rate(s: &str) -> Result<u64> {
+    parse_units(s.trim().to_uppercase().trim_end_matches("/S"))
+        .ok_or_else(|| anyhow!("Invalid rate {:?}, should be like 20M or 512K", s))
+}
+
+// Parse a size like `10G` or `500MB`.
+pub fn parse_size(s: &str) -> Result<u64> {
+    parse_units(&s.trim().to_uppercase()).ok_or_else(|| anyhow!("Invalid size {:?}, should be like 10G or 500M", s))
+}
+
+// `K`, `M` and `G` are powers of 1024, the same as in the progress.
+fn parse_units(s: &str) -> Option<u64> {
+    let digits = s.trim_end_matches('B');
+    let (number, unit) = match digits.char_indices().last() {
+        Some((i, 'K')) => (&digits[..i], 1u64 << 10),
+        Some((i, 'M')) => (&digits[..i], 1 << 20),
+        Some((i, 'G')) => (&digits[..i], 1 << 30),
+        _ => (digits, 1),
+    };
+
+    number.parse::<f64>().map(|n| (n * unit as f64) as u64).ok().filter(|n| *n > 0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_rate_test() {
+        assert_eq!(parse_rate("20M").unwrap(), 20 << 20);
+        assert_eq!(parse_rate("512kb").unwrap(), 512 << 10);
+        assert_eq!(parse_rate("1.5G").unwrap(), 3 << 29);
+        assert_eq!(parse_rate("1000").unwrap(), 1000);
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("0M").is_err());
+        assert_eq!(parse_size("10G").unwrap(), 10 << 30);
+        assert!(parse_size("20M/s").is_err());
+    }
+
+    #[test]
+    fn quota_test() {
+        let mut quota = Quota::new(Some(2), Some(1000));
+        quota.take(1, 600).unwrap();
+        assert!(quota.check(1, 500).unwrap_err().to_string().contains("1000B"));
+        quota.take(1, 400).unwrap();
+        assert!(quota.take(1, 0).unwrap_err().to_string().contains("2 files"));
+        assert!(Quota::default().check(u64::MAX / 2, u64::MAX / 2).is_ok());
+    }
+
+    #[test]
+    fn bucket_test() {
+        let mut bucket = Bucket { rate: 1000.0, tokens: 1000.0, last: Instant::now() };
+        assert_eq!(bucket.reserve(600.0), Duration::ZERO);
+
+        // Twice the rate from a full bucket is paid back in about one second.
+        let wait = bucket.reserve(1400.0);
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+    }
+}
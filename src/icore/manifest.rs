@@ -0,0 +1,242 @@
+use anyhow::{anyhow, Result};
+use std::convert::TryInto;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use super::meta::Meta;
+
+// Version of the manifest layout, the first byte.
+const MANIFEST_VERSION: u8 = 1;
+
+// Layout: 1 byte version, then u64 files, u64 dirs and u64 bytes,
+// followed by the items in the order they are sent, each of them:
+// 1 byte kind, u16 depth, u64 files and u64 dirs inside, 4 bytes length and the meta record with the size.
+const MANIFEST_SIZE: usize = 25;
+const ITEM_HEADER: usize = 23;
+
+const KIND_DIR: u8 = 1;
+const KIND_FILE: u8 = 2;
+
+// Over this size, only the top level items are listed, the totals are still complete.
+const MAX_ITEMS_SIZE: usize = 0x1000000;
+
+// What the sender is going to send, announced before the first file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Manifest {
+    pub files: u64,
+    pub dirs: u64,
+    pub bytes: u64,     // size of all file content.
+    pub items: Vec<Item>,
+}
+
+// A file or directory in the manifest. A directory has the totals of everything inside.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Item {
+    pub name: OsString,
+    pub dir: bool,
+    pub depth: u16,     // 0 for the files and directories given to the sender.
+    pub files: u64,
+    pub dirs: u64,
+    pub bytes: u64,
+}
+
+impl Manifest {
+    // Walk the files and directories to send, the same way as the sender does.
+    // Entries which cannot be read are left out, the sender reports them when it gets there.
+    pub fn collect(paths: &[PathBuf]) -> Self {
+        let mut items = Vec::new();
+        for path in paths {
+            walk(path, 0, &mut items);
+        }
+
+        Manifest::from_items(items)
+    }
+
+    // The totals are of the top level items, which have everything inside.
+    // The items may come from the other side, so the sums saturate instead of overflowing.
+    fn from_items(items: Vec<Item>) -> Self {
+        let mut manifest = Manifest::default();
+        for item in items.iter().filter(|i| i.depth == 0) {
+            manifest.files = manifest.files.saturating_add(item.files);
+            manifest.dirs = manifest.dirs.saturating_add(item.dirs).saturating_add(u64::from(item.dir));
+            manifest.bytes = manifest.bytes.saturating_add(item.bytes);
+        }
+        manifest.items = items;
+
+        manifest
+    }
+
+    // Whether `path` is listed as a top level item, the same as the sender sends it.
+    pub fn is_listed(path: &Path) -> bool {
+        path.file_name().is_some() && (path.is_file() || path.is_dir())
+    }
+
+    pub fn top_items(&self) -> impl Iterator<Item = &Item> {
+        self.items.iter().filter(|i| i.depth == 0)
+    }
+
+    // The manifest without the top level items at the `rejected` indices.
+    // The first item is at the top level, `decode()` makes sure of it.
+    pub fn pick(&self, rejected: &[usize]) -> Self {
+        let mut items = Vec::new();
+        let mut top = 0;
+        for item in &self.items {
+            if item.depth == 0 {
+                top += 1;
+            }
+            if !rejected.contains(&(top - 1)) {
+                items.push(item.clone());
+            }
+        }
+
+        Manifest::from_items(items)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![MANIFEST_VERSION];
+        buf.extend_from_slice(&self.files.to_be_bytes());
+        buf.extend_from_slice(&self.dirs.to_be_bytes());
+        buf.extend_from_slice(&self.bytes.to_be_bytes());
+
+        let mut items = Vec::new();
+        for item in &self.items {
+            item.encode(&mut items);
+        }
+
+        if items.len() > MAX_ITEMS_SIZE {
+            items.clear();
+            for item in self.top_items() {
+                item.encode(&mut items);
+            }
+        }
+        buf.extend_from_slice(&items);
+
+        buf
+    }
+
+    // The items start at the top level and go at most one level deeper each time,
+    // as `walk()` lists them.
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        match buf.first() {
+            Some(&MANIFEST_VERSION) if buf.len() >= MANIFEST_SIZE => (),
+            Some(&MANIFEST_VERSION) => return Err(anyhow!("Truncated manifest")),
+            Some(v) => return Err(anyhow!("Unsupported manifest version {}", v)),
+            None => return Err(anyhow!("Empty manifest")),
+        }
+
+        let field = |i: usize| u64::from_be_bytes(buf[1 + i * 8..9 + i * 8].try_into().unwrap());
+        let mut manifest = Manifest { files: field(0), dirs: field(1), bytes: field(2), items: Vec::new() };
+
+        let mut rest = &buf[MANIFEST_SIZE..];
+        while !rest.is_empty() {
+            if rest.len() < ITEM_HEADER {
+                return Err(anyhow!("Truncated manifest"));
+            }
+
+            let len = u32::from_be_bytes(rest[19..23].try_into().unwrap()) as usize;
+            if rest.len() < ITEM_HEADER + len {
+                return Err(anyhow!("Truncated manifest"));
+            }
+
+            let depth = u16::from_be_bytes([rest[1], rest[2]]);
+            if depth > manifest.items.last().map_or(0, |i| i.depth.saturating_add(1)) {
+                return Err(anyhow!("Invalid depth {} in manifest", depth));
+            }
+
+            let meta = Meta::decode(&rest[ITEM_HEADER..ITEM_HEADER + len])?;
+            manifest.items.push(Item {
+                name: meta.name,
+                dir: rest[0] == KIND_DIR,
+                depth,
+                files: u64::from_be_bytes(rest[3..11].try_into().unwrap()),
+                dirs: u64::from_be_bytes(rest[11..19].try_into().unwrap()),
+                bytes: meta.size.unwrap_or_default(),
+            });
+            rest = &rest[ITEM_HEADER + len..];
+        }
+
+        Ok(manifest)
+    }
+}
+
+impl Item {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let meta = Meta { size: Some(self.bytes), name: self.name.clone(), ..Default::default() }.encode();
+        buf.push(if self.dir { KIND_DIR } else { KIND_FILE });
+        buf.extend_from_slice(&self.depth.to_be_bytes());
+        buf.extend_from_slice(&self.files.to_be_bytes());
+        buf.extend_from_slice(&self.dirs.to_be_bytes());
+        buf.extend_from_slice(&(meta.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&meta);
+    }
+}
+
+// Add the item of `path` and everything inside, and fill the totals of a directory after its content.
+fn walk(path: &Path, depth: u16, items: &mut Vec<Item>) -> Option<(u64, u64, u64)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    let name = path.file_name()?.to_os_string();
+
+    if metadata.is_file() {
+        items.push(Item { name, depth, files: 1, bytes: metadata.len(), ..Default::default() });
+        return Some((1, 0, metadata.len()));
+    }
+    if !metadata.is_dir() {
+        return None;
+    }
+
+    let index = items.len();
+    items.push(Item { name, dir: true, depth, ..Default::default() });
+    let (mut files, mut dirs, mut bytes) = (0, 0, 0);
+    for entry in path.read_dir().ok()?.flatten() {
+        if let Some((f, d, b)) = walk(&entry.path(), depth + 1, items) {
+            files += f;
+            dirs += d;
+            bytes += b;
+        }
+    }
+
+    let dir = &mut items[index];
+    dir.files = files;
+    dir.dirs = dirs;
+    dir.bytes = bytes;
+
+    Some((files, dirs + 1, bytes))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn manifest_test() {
+        let root = std::env::temp_dir().join(format!("isend_manifest_{}", std::process::id()));
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("a.txt"), b"hello").unwrap();
+        std::fs::write(root.join("sub").join("b.bin"), vec![0u8; 1000]).unwrap();
+        std::fs::write(root.join("c.txt"), b"top").unwrap();
+
+        let manifest = Manifest::collect(&[root.clone(), root.join("missing"), root.join("c.txt")]);
+        std::fs::remove_dir_all(&root).unwrap();
+        // c.txt is listed both inside the directory and on its own.
+        assert_eq!((manifest.files, manifest.dirs, manifest.bytes), (4, 2, 1011));
+        assert_eq!(manifest.items.len(), 6);
+        assert_eq!(manifest.top_items().count(), 2);
+
+        let decoded = Manifest::decode(&manifest.encode()).unwrap();
+        assert_eq!(decoded, manifest);
+        assert!(Manifest::decode(&manifest.encode()[..30]).is_err());
+
+        let picked = manifest.pick(&[0]);
+        assert_eq!((picked.files, picked.dirs, picked.bytes, picked.items.len()), (1, 0, 3, 1));
+    }
+
+    #[test]
+    fn manifest_depth_test() {
+        let item = |depth| Item { name: OsString::from("a"), dir: true, depth, ..Default::default() };
+        let manifest = |depths: &[u16]| Manifest { items: depths.iter().map(|d| item(*d)).collect(), ..Default::default() };
+
+        assert!(Manifest::decode(&manifest(&[0, 1, 2, 1, 0]).encode()).is_ok());
+        // Starting below the top level, or skipping a level, cannot come from a directory walk.
+        assert!(Manifest::decode(&manifest(&[1, 2]).encode()).is_err());
+        assert!(Manifest::decode(&manifest(&[0, 2]).encode()).is_err());
+    }
+}
@@ -1,4 +1,5 @@
 use anyhow::Result;
+use std::cell::RefCell;
 use std::sync::mpsc::{Receiver, Sender};
 use std::sync::{mpsc, Mutex};
 
@@ -30,6 +31,11 @@ lazy_static::lazy_static!{
     };
 }
 
+async_std::task_local! {
+    // Label of the daemon session the current task runs, empty outside of one.
+    static SESSION: RefCell<String> = RefCell::new(String::new());
+}
+
 // This function should be invoked by typer to register the channels.
 pub fn launch(rx: Receiver<String>, tx: Sender<Message>) {
     let mut pipe = PIPE.lock().unwrap();
@@ -40,6 +46,14 @@ pub fn launch(rx: Receiver<String>, tx: Sender<Message>) {
 // public interface to send message to UI.
 // The errors should be handled here since no other way to display them.
 pub fn send_msg(msg: Message) {
+    let msg = match SESSION.try_with(|s| s.borrow().clone()) {
+        Ok(label) if !label.is_empty() => match in_session(msg, &label) {
+            Some(msg) => msg,
+            None => return,
+        },
+        _ => msg,
+    };
+
     let pipe = PIPE.lock().unwrap();
     let tx = &pipe.tx;
 
@@ -57,7 +71,7 @@ pub fn send_prompt(msg: Message) -> String {
             Err(e) => send_msg(Message::Error(format!("in receiving user input: {}", e))),
         }
     } else {
-        send_msg(Message::Error(format!("wrong format in sending prompt")));
+        send_msg(Message::Error("wrong format in sending prompt".to_string()));
     }
 
     String::new()
@@ -70,3 +84,33 @@ fn recv_input() -> Result<String> {
 
     Ok(rx.recv()?)
 }
+
+// Label the messages of the current task as the daemon session's, see in_session().
+pub fn label_session(label: &str) {
+    SESSION.with(|s| *s.borrow_mut() = label.to_string());
+}
+
+// The sessions of a daemon run side by side, so their messages become lines
+// with the label in front. Progress cannot share the lines, and no session ends the process.
+fn in_session(msg: Message, label: &str) -> Option<Message> {
+    match msg {
+        Message::Status(s) => Some(Message::Status(format!("[{}] {}", label, s.trim()))),
+        Message::Error(s) | Message::Fatal(s) => Some(Message::Error(format!("[{}] {}", label, s))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn in_session_test() {
+        let status = in_session(Message::Status("Connection established\n".to_string()), "1 10.0.0.5");
+        assert!(matches!(status, Some(Message::Status(s)) if s == "[1 10.0.0.5] Connection established"));
+        let fatal = in_session(Message::Fatal("Lost".to_string()), "2 10.0.0.6");
+        assert!(matches!(fatal, Some(Message::Error(s)) if s == "[2 10.0.0.6] Lost"));
+        assert!(in_session(Message::Done, "1 10.0.0.5").is_none());
+        assert!(in_session(Message::Progress("50%".to_string()), "1 10.0.0.5").is_none());
+    }
+}
@@ -0,0 +1,420 @@
+use anyhow::{anyhow, Result};
+use std::convert::TryInto;
+use std::ffi::{OsStr, OsString};
+use std::fs::{FileTimes, Metadata};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use super::arg::KeepAttr;
+
+// Version of the meta record layout, the first byte of every record.
+pub const META_VERSION: u8 = 1;
+
+// Field tags. Each field is encoded as 1 byte tag, 4 bytes length and the value,
+// so the other side can skip fields it doesn't know.
+const TAG_SIZE: u8 = 1;     // u64
+const TAG_NAME: u8 = 2;     // 1 byte encoding followed by the name bytes
+const TAG_MODE: u8 = 3;     // u32
+const TAG_MTIME: u8 = 4;    // u64 seconds and u32 nanoseconds since Unix epoch
+const TAG_ATIME: u8 = 5;
+const TAG_UID: u8 = 6;      // u32
+const TAG_GID: u8 = 7;
+
+// How the name bytes are encoded on the sender's system.
+const NAME_UTF8: u8 = 0;
+const NAME_UNIX: u8 = 1;    // raw bytes, may not be UTF-8
+const NAME_WINDOWS: u8 = 2; // WTF-8, may have unpaired surrogates
+
+// Max bytes of a received name, so the part file `.{name}.part` still fits in the usual limit of 255.
+const MAX_NAME: usize = 249;
+
+// Device names on Windows, in any directory and with any extension.
+const RESERVED: [&str; 22] = ["CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7",
+    "COM8", "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9"];
+
+// Meta info of a file or directory, sent before its content.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Meta {
+    pub size: Option<u64>,      // None for directories.
+    pub name: OsString,
+    pub attr: FileAttr,
+}
+
+// File attributes to keep on the receiver side.
+// Each one is optional as the sender's system may not have it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FileAttr {
+    pub mode: Option<u32>,          // Unix permission bits.
+    pub mtime: Option<Duration>,    // since Unix epoch.
+    pub atime: Option<Duration>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl Meta {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![META_VERSION];
+        if let Some(size) = self.size {
+            put_field(&mut buf, TAG_SIZE, &size.to_be_bytes());
+        }
+        put_field(&mut buf, TAG_NAME, &encode_name(&self.name));
+
+        let attr = &self.attr;
+        if let Some(mode) = attr.mode {
+            put_field(&mut buf, TAG_MODE, &mode.to_be_bytes());
+        }
+        if let Some(t) = attr.mtime {
+            put_field(&mut buf, TAG_MTIME, &encode_time(t));
+        }
+        if let Some(t) = attr.atime {
+            put_field(&mut buf, TAG_ATIME, &encode_time(t));
+        }
+        if let Some(uid) = attr.uid {
+            put_field(&mut buf, TAG_UID, &uid.to_be_bytes());
+        }
+        if let Some(gid) = attr.gid {
+            put_field(&mut buf, TAG_GID, &gid.to_be_bytes());
+        }
+
+        buf
+    }
+
+    // Newer records with unknown fields are still read, a newer layout version is not.
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        match buf.first() {
+            Some(&META_VERSION) => (),
+            Some(v) => return Err(anyhow!("Unsupported meta version {}", v)),
+            None => return Err(anyhow!("Empty meta record")),
+        }
+
+        let mut meta = Meta::default();
+        let mut has_name = false;
+        let mut rest = &buf[1..];
+
+        while !rest.is_empty() {
+            if rest.len() < 5 {
+                return Err(anyhow!("Truncated meta record"));
+            }
+
+            let tag = rest[0];
+            let len = u32::from_be_bytes([rest[1], rest[2], rest[3], rest[4]]) as usize;
+            if rest.len() < 5 + len {
+                return Err(anyhow!("Truncated meta record"));
+            }
+
+            let value = &rest[5..5 + len];
+            rest = &rest[5 + len..];
+
+            match tag {
+                TAG_SIZE => meta.size = Some(u64::from_be_bytes(fixed(value)?)),
+                TAG_NAME => {
+                    meta.name = decode_name(value)?;
+                    has_name = true;
+                },
+                TAG_MODE => meta.attr.mode = Some(u32::from_be_bytes(fixed(value)?)),
+                TAG_MTIME => meta.attr.mtime = Some(decode_time(value)?),
+                TAG_ATIME => meta.attr.atime = Some(decode_time(value)?),
+                TAG_UID => meta.attr.uid = Some(u32::from_be_bytes(fixed(value)?)),
+                TAG_GID => meta.attr.gid = Some(u32::from_be_bytes(fixed(value)?)),
+                _ => log::debug!("Unknown meta field {}", tag),
+            }
+        }
+
+        if !has_name {
+            return Err(anyhow!("No name in meta record"));
+        }
+
+        Ok(meta)
+    }
+}
+
+impl FileAttr {
+    // Read the attributes of a local file. used in sender.
+    pub fn from(metadata: &Metadata) -> Self {
+        let since_epoch = |t: std::io::Result<SystemTime>| {
+            t.ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        };
+
+        let mut attr = FileAttr {
+            mtime: since_epoch(metadata.modified()),
+            atime: since_epoch(metadata.accessed()),
+            ..Default::default()
+        };
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            attr.mode = Some(metadata.mode() & 0o7777);
+            attr.uid = Some(metadata.uid());
+            attr.gid = Some(metadata.gid());
+        }
+
+        attr
+    }
+
+    // Apply the attributes chosen to keep to the received file.
+    // Set-user-ID and set-group-ID bits from the other side are never applied.
+    pub fn apply(&self, path: &Path, keep: &KeepAttr) -> Result<()> {
+        let mut times = FileTimes::new();
+        if let (true, Some(t)) = (keep.mtime, self.mtime) {
+            times = times.set_modified(UNIX_EPOCH + t);
+        }
+        if let (true, Some(t)) = (keep.atime, self.atime) {
+            times = times.set_accessed(UNIX_EPOCH + t);
+        }
+        std::fs::OpenOptions::new().write(true).open(path)?.set_times(times)?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            if keep.owner {
+                std::os::unix::fs::chown(path, self.uid, self.gid)?;
+            }
+            if let (true, Some(mode)) = (keep.mode, self.mode) {
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o1777))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Show a name for messages, with the bytes which are not valid UTF-8 escaped.
+pub fn display_name(name: &OsStr) -> String {
+    match name.to_str() {
+        Some(s) => String::from(s),
+        None => escape_bytes(name.as_encoded_bytes()),
+    }
+}
+
+// Check a name from the other side before it's joined to the receiving directory.
+// It must be one plain component, so nothing can be written outside the directory.
+// The error is the reason to refuse it.
+pub fn check_name(name: &OsStr) -> Result<()> {
+    check_name_for(name, cfg!(windows))
+}
+
+fn check_name_for(name: &OsStr, windows: bool) -> Result<()> {
+    let bytes = name.as_encoded_bytes();
+    if bytes.is_empty() {
+        return Err(anyhow!("empty name"));
+    }
+    if bytes == b"." || bytes == b".." {
+        return Err(anyhow!("name refers to a directory"));
+    }
+    if bytes.contains(&0) {
+        return Err(anyhow!("name has a NUL byte"));
+    }
+    if bytes.iter().any(|b| *b == b'/' || *b == b'\\') {
+        return Err(anyhow!("name has a path separator"));
+    }
+    if bytes.len() > MAX_NAME {
+        return Err(anyhow!("name is longer than {} bytes", MAX_NAME));
+    }
+
+    if windows {
+        if bytes.iter().any(|b| *b < 0x20 || b"<>:\"|?*".contains(b)) {
+            return Err(anyhow!("name has a character not allowed on Windows"));
+        }
+        if bytes.ends_with(b".") || bytes.ends_with(b" ") {
+            return Err(anyhow!("name ends with a dot or space"));
+        }
+
+        let stem = bytes.split(|b| *b == b'.').next().unwrap_or_default();
+        let stem = std::str::from_utf8(stem).unwrap_or_default().trim_end();
+        if RESERVED.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+            return Err(anyhow!("name is reserved for a device on Windows"));
+        }
+    }
+
+    Ok(())
+}
+
+fn put_field(buf: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    buf.push(tag);
+    buf.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    buf.extend_from_slice(value);
+}
+
+fn fixed<const N: usize>(value: &[u8]) -> Result<[u8; N]> {
+    value.try_into().map_err(|_| anyhow!("Invalid meta field length"))
+}
+
+fn encode_time(t: Duration) -> Vec<u8> {
+    let mut buf = t.as_secs().to_be_bytes().to_vec();
+    buf.extend_from_slice(&t.subsec_nanos().to_be_bytes());
+
+    buf
+}
+
+fn decode_time(value: &[u8]) -> Result<Duration> {
+    let value: [u8; 12] = fixed(value)?;
+    let secs = u64::from_be_bytes(fixed(&value[..8])?);
+    let nanos = u32::from_be_bytes(fixed(&value[8..])?);
+    if nanos >= 1_000_000_000 {
+        return Err(anyhow!("Invalid time in meta record"));
+    }
+
+    Ok(Duration::new(secs, nanos))
+}
+
+// The name bytes as they are on the sender's system, tagged with the encoding.
+fn encode_name(name: &OsStr) -> Vec<u8> {
+    let encoding = match name.to_str() {
+        Some(_) => NAME_UTF8,
+        None if cfg!(unix) => NAME_UNIX,
+        None => NAME_WINDOWS,
+    };
+
+    let mut buf = vec![encoding];
+    buf.extend_from_slice(name.as_encoded_bytes());
+
+    buf
+}
+
+// Unix takes any bytes as a name, so names are kept exactly as they are.
+// Elsewhere the bytes which are not valid UTF-8 are escaped as `%XX`, and so is a `%` which would
+// read as an escape (as `%25`), so different names never come out the same.
+fn decode_name(value: &[u8]) -> Result<OsString> {
+    let (encoding, bytes) = match value.split_first() {
+        Some((e, b)) => (*e, b),
+        None => return Err(anyhow!("Empty name in meta record")),
+    };
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        if encoding != NAME_UTF8 {
+            log::debug!("Keep raw name in encoding {}", encoding);
+        }
+        Ok(OsStr::from_bytes(bytes).to_os_string())
+    }
+
+    #[cfg(not(unix))]
+    {
+        if encoding != NAME_UTF8 {
+            log::debug!("Escape name in encoding {}", encoding);
+        }
+        Ok(OsString::from(escape_bytes(bytes)))
+    }
+}
+
+fn escape_bytes(bytes: &[u8]) -> String {
+    let mut escaped = String::new();
+    let mut rest = bytes;
+
+    while !rest.is_empty() {
+        match std::str::from_utf8(rest) {
+            Ok(s) => {
+                escape_percent(s, &mut escaped);
+                break;
+            },
+            Err(e) => {
+                let (valid, invalid) = rest.split_at(e.valid_up_to());
+                escape_percent(std::str::from_utf8(valid).unwrap_or_default(), &mut escaped);
+                let bad = e.error_len().unwrap_or(invalid.len());
+                for b in &invalid[..bad] {
+                    escaped.push_str(&format!("%{:02X}", b));
+                }
+                rest = &invalid[bad..];
+            }
+        }
+    }
+
+    escaped
+}
+
+// A `%` followed by two hex digits reads as an escape, so it's escaped itself.
+fn escape_percent(s: &str, escaped: &mut String) {
+    for (i, c) in s.char_indices() {
+        let hex = s.as_bytes().get(i + 1..i + 3).is_some_and(|h| h.iter().all(u8::is_ascii_hexdigit));
+        if c == '%' && hex {
+            escaped.push_str("%25");
+        } else {
+            escaped.push(c);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn meta_roundtrip_test() {
+        let meta = Meta {
+            size: Some(2954040),
+            name: OsString::from("notes:v2;final.txt"),
+            attr: FileAttr {
+                mode: Some(0o755),
+                mtime: Some(Duration::new(1600000000, 500)),
+                atime: Some(Duration::new(1600000001, 0)),
+                uid: Some(1000),
+                gid: Some(100),
+            },
+        };
+        assert_eq!(Meta::decode(&meta.encode()).unwrap(), meta);
+
+        let dir = Meta { name: OsString::from("dir"), ..Default::default() };
+        assert_eq!(Meta::decode(&dir.encode()).unwrap().size, None);
+    }
+
+    #[test]
+    fn meta_unknown_field_test() {
+        let mut buf = Meta { size: Some(1), name: OsString::from("a"), ..Default::default() }.encode();
+        put_field(&mut buf, 99, b"from the future");
+        assert_eq!(Meta::decode(&buf).unwrap().name, "a");
+
+        assert!(Meta::decode(&[2]).is_err());
+        assert!(Meta::decode(&buf[..buf.len() - 1]).is_err());
+        assert!(Meta::decode(&[META_VERSION]).is_err());
+    }
+
+    #[test]
+    fn check_name_test() {
+        for name in ["a.txt", "notes:v2;final.txt", ".hidden", "..a", "con.d"] {
+            assert!(check_name_for(OsStr::new(name), false).is_ok(), "{}", name);
+        }
+        for name in ["", ".", "..", "../../.bashrc", "/etc/passwd", "a\\b", "a\0b", &"x".repeat(250)] {
+            assert!(check_name_for(OsStr::new(name), false).is_err(), "{}", name);
+        }
+
+        for name in ["CON", "nul.txt", "Com1 .tar.gz", "C:x", "a?", "dot.", "tab\t"] {
+            assert!(check_name_for(OsStr::new(name), true).is_err(), "{}", name);
+        }
+        assert!(check_name_for(OsStr::new("console.txt"), true).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn meta_raw_name_test() {
+        use std::os::unix::ffi::OsStrExt;
+        let name = OsStr::from_bytes(b"caf\xe9.txt").to_os_string();
+        let meta = Meta { size: Some(1), name: name.clone(), ..Default::default() };
+        assert_eq!(Meta::decode(&meta.encode()).unwrap().name, name);
+
+        // Any bytes from another system are a valid name here.
+        assert_eq!(decode_name(b"\x02caf\xe9.txt").unwrap(), name);
+        assert_eq!(display_name(&name), "caf%E9.txt");
+    }
+
+    #[test]
+    fn meta_escape_name_test() {
+        fn unescape(s: &str) -> Vec<u8> {
+            let (mut bytes, mut rest) = (vec![], s.as_bytes());
+            while let Some((&b, tail)) = rest.split_first() {
+                match tail.get(..2).and_then(|h| u8::from_str_radix(std::str::from_utf8(h).ok()?, 16).ok()) {
+                    Some(v) if b == b'%' => { bytes.push(v); rest = &tail[2..]; },
+                    _ => { bytes.push(b); rest = tail; },
+                }
+            }
+            bytes
+        }
+
+        let names: [&[u8]; 4] = [b"a%FFb", b"a\xFFb", b"100%.txt", b"%\xe9%2"];
+        let escaped: Vec<String> = names.iter().map(|n| escape_bytes(n)).collect();
+        assert_eq!(escaped, ["a%25FFb", "a%FFb", "100%.txt", "%%E9%2"]);
+        for (name, escaped) in names.iter().zip(&escaped) {
+            assert_eq!(unescape(escaped), *name);
+        }
+    }
+}
@@ -1,8 +1,22 @@
 pub mod arg;
+pub mod code;
+pub mod limit;
 pub mod message;
 pub mod receiver;
+pub mod relay;
 pub mod sender;
 
+mod archive;
+mod conn;
 mod currentfile;
+mod hello;
 mod instruction;
-mod utils;
\ No newline at end of file
+mod interrupt;
+mod manifest;
+mod meta;
+mod net;
+mod pake;
+mod stats;
+mod streams;
+mod utils;
+mod wordlist;
\ No newline at end of file
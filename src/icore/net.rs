@@ -0,0 +1,250 @@
+use anyhow::{anyhow, Result};
+use async_std::net::{TcpListener, UdpSocket};
+use if_addrs::{IfAddr, Interface};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+
+// Link-local multicast group for IPv6 discovery, used instead of broadcast.
+pub const MULTICAST_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0x4953, 0x4e44);
+
+// Find the local interface addresses given by name (e.g. `eth0`, `lo`) or by address.
+// An empty list means no limit, i.e. all interfaces.
+pub fn select_interfaces(names: &[String]) -> Result<Vec<Interface>> {
+    if names.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let all = if_addrs::get_if_addrs()?;
+    let mut selected = Vec::new();
+
+    for name in names {
+        let matched: Vec<Interface> = all.iter()
+            .filter(|i| &i.name == name || i.ip().to_string() == *name)
+            .cloned()
+            .collect();
+
+        if matched.is_empty() {
+            return Err(anyhow!("No network interface found for {:?}", name));
+        }
+
+        selected.extend(matched);
+    }
+
+    Ok(selected)
+}
+
+// Bind an IPv6 only UDP socket on the port, so it can share the port with an IPv4 one.
+// Join the discovery group on the IPv6 interfaces to receive the multicast from receivers.
+pub fn bind_udp_v6(port: u16, ifaces: &[Interface]) -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+
+    let udp: std::net::UdpSocket = socket.into();
+    for index in v6_interfaces(ifaces) {
+        if let Err(e) = udp.join_multicast_v6(&MULTICAST_V6, index) {
+            log::debug!("Cannot join multicast group on interface {}: {}", index, e);
+        }
+    }
+
+    Ok(UdpSocket::from(udp))
+}
+
+// Listen on the address of each selected interface, all on the same port.
+// Without selection, listen on both IPv4 and IPv6 with a dual-stack socket,
+// or IPv4 only if the system doesn't support IPv6.
+pub fn bind_tcp_listeners(ifaces: &[Interface]) -> Result<Vec<TcpListener>> {
+    if ifaces.is_empty() {
+        let listener = match bind_tcp_dual_stack() {
+            Ok(l) => l,
+            Err(e) => {
+                log::debug!("Cannot listen on IPv6, use IPv4 only: {}", e);
+                std::net::TcpListener::bind(("0.0.0.0", 0))?
+            }
+        };
+
+        return Ok(vec![TcpListener::from(listener)]);
+    }
+
+    let mut listeners = Vec::new();
+    let mut port = 0;
+    for iface in ifaces {
+        let listener = std::net::TcpListener::bind(interface_addr(iface, port))?;
+        port = listener.local_addr()?.port();
+        listeners.push(TcpListener::from(listener));
+    }
+
+    Ok(listeners)
+}
+
+fn bind_tcp_dual_stack() -> Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_only_v6(false)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)).into())?;
+    socket.listen(128)?;
+
+    Ok(socket.into())
+}
+
+// IPv4 discovery targets: the subnet broadcast address of each selected interface,
+// or the interface address itself if it has no broadcast, like loopback.
+// Without selection, use the global broadcast address.
+pub fn broadcast_targets(port: u16, ifaces: &[Interface]) -> Vec<SocketAddr> {
+    if ifaces.is_empty() {
+        return vec![SocketAddr::from((Ipv4Addr::BROADCAST, port))];
+    }
+
+    ifaces.iter()
+        .filter_map(|i| match &i.addr {
+            IfAddr::V4(v4) => Some(SocketAddr::from((v4.broadcast.unwrap_or(v4.ip), port))),
+            _ => None,
+        })
+        .collect()
+}
+
+// Multicast targets of the discovery group on the IPv6 interfaces.
+// Link-local addresses only make sense with the scope id of the interface.
+pub fn multicast_targets(port: u16, ifaces: &[Interface]) -> Vec<SocketAddr> {
+    v6_interfaces(ifaces).into_iter()
+        .map(|index| SocketAddr::V6(SocketAddrV6::new(MULTICAST_V6, port, 0, index)))
+        .collect()
+}
+
+// Check whether the remote address is reachable from the selected interfaces,
+// i.e. in the same IPv4 subnet, or on the same IPv6 link.
+pub fn in_interfaces(addr: &SocketAddr, ifaces: &[Interface]) -> bool {
+    if ifaces.is_empty() {
+        return true;
+    }
+
+    ifaces.iter().any(|i| match (&i.addr, canonical(*addr)) {
+        (IfAddr::V4(v4), SocketAddr::V4(remote)) => {
+            let mask = u32::from(v4.netmask);
+            u32::from(v4.ip) & mask == u32::from(*remote.ip()) & mask
+        },
+        (IfAddr::V6(v6), SocketAddr::V6(remote)) => {
+            if is_link_local(remote.ip()) {
+                Some(remote.scope_id()) == i.index
+            } else {
+                let mask = u128::from(v6.netmask);
+                u128::from(v6.ip) & mask == u128::from(*remote.ip()) & mask
+            }
+        },
+        _ => false,
+    })
+}
+
+// Socket address on the interface, with the scope id for link-local IPv6.
+fn interface_addr(iface: &Interface, port: u16) -> SocketAddr {
+    match iface.ip() {
+        IpAddr::V6(ip) if is_link_local(&ip) => {
+            SocketAddr::V6(SocketAddrV6::new(ip, port, 0, iface.index.unwrap_or(0)))
+        },
+        ip => SocketAddr::new(ip, port),
+    }
+}
+
+// Index of the interfaces with an IPv6 address, excluding loopback.
+// Only the selected ones if any.
+fn v6_interfaces(ifaces: &[Interface]) -> Vec<u32> {
+    let all = if ifaces.is_empty() {
+        match if_addrs::get_if_addrs() {
+            Ok(all) => all,
+            Err(e) => {
+                log::debug!("Cannot read network interfaces: {}", e);
+                Vec::new()
+            }
+        }
+    } else {
+        ifaces.to_vec()
+    };
+
+    let mut indexes: Vec<u32> = all.into_iter()
+        .filter(|i| !i.is_loopback() && i.ip().is_ipv6())
+        .filter_map(|i| i.index)
+        .collect();
+
+    indexes.sort_unstable();
+    indexes.dedup();
+
+    indexes
+}
+
+fn is_link_local(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xffc0 == 0xfe80
+}
+
+// Show IPv4-mapped addresses from the dual-stack socket as plain IPv4 ones.
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(v4) => SocketAddr::from((v4, v6.port())),
+            None => addr,
+        },
+        _ => addr,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use if_addrs::{Ifv4Addr, Ifv6Addr};
+
+    fn iface_v4(ip: &str, netmask: &str) -> Interface {
+        Interface {
+            name: String::from("test0"),
+            addr: IfAddr::V4(Ifv4Addr {
+                ip: ip.parse().unwrap(),
+                netmask: netmask.parse().unwrap(),
+                prefixlen: 24,
+                broadcast: None,
+            }),
+            index: Some(2),
+            #[cfg(windows)]
+            adapter_name: String::new(),
+        }
+    }
+
+    fn iface_v6(ip: &str) -> Interface {
+        Interface {
+            name: String::from("test0"),
+            addr: IfAddr::V6(Ifv6Addr {
+                ip: ip.parse().unwrap(),
+                netmask: "ffff:ffff:ffff:ffff::".parse().unwrap(),
+                prefixlen: 64,
+                broadcast: None,
+            }),
+            index: Some(2),
+            #[cfg(windows)]
+            adapter_name: String::new(),
+        }
+    }
+
+    #[test]
+    fn canonical_addr_test() {
+        let mapped: SocketAddr = "[::ffff:192.168.1.5]:8080".parse().unwrap();
+        assert_eq!(canonical(mapped), "192.168.1.5:8080".parse().unwrap());
+
+        let scoped = SocketAddr::V6(SocketAddrV6::new("fe80::1".parse().unwrap(), 8080, 0, 2));
+        assert_eq!(canonical(scoped), scoped);
+        assert_eq!(scoped.to_string(), "[fe80::1%2]:8080");
+    }
+
+    #[test]
+    fn in_interfaces_test() {
+        let ifaces = vec![iface_v4("192.168.1.10", "255.255.255.0"), iface_v6("fe80::1")];
+        assert!(in_interfaces(&"192.168.1.20:80".parse().unwrap(), &ifaces));
+        assert!(in_interfaces(&"[::ffff:192.168.1.20]:80".parse().unwrap(), &ifaces));
+        assert!(!in_interfaces(&"10.0.0.1:80".parse().unwrap(), &ifaces));
+        assert!(in_interfaces(&"[fe80::2%2]:80".parse().unwrap(), &ifaces));
+        assert!(!in_interfaces(&"[fe80::2%3]:80".parse().unwrap(), &ifaces));
+        assert!(in_interfaces(&"10.0.0.1:80".parse().unwrap(), &[]));
+    }
+
+    #[test]
+    fn broadcast_targets_test() {
+        let lo = iface_v4("127.0.0.1", "255.0.0.0");
+        assert_eq!(broadcast_targets(9, &[lo]), vec!["127.0.0.1:9".parse().unwrap()]);
+        assert_eq!(broadcast_targets(9, &[]), vec!["255.255.255.255:9".parse().unwrap()]);
+    }
+}
@@ -0,0 +1,96 @@
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+
+pub type PakeState = Spake2<Ed25519Group>;
+
+// Length of the key exchange message: the side and a curve point.
+pub const MSG_SIZE: usize = 33;
+
+// Length of the key confirmation tag.
+pub const CONFIRM_SIZE: usize = 32;
+
+const SENDER_ID: &[u8] = b"isend sender";
+const RECEIVER_ID: &[u8] = b"isend receiver";
+
+// Start the password-authenticated key exchange (SPAKE2).
+// The sender is side A and the receiver is side B.
+// No password is the same as an empty one, so both sides must agree on that too.
+// Return the state to finish with and the message to send to the other side.
+pub fn start(password: Option<&String>, sender: bool) -> (PakeState, Vec<u8>) {
+    let pw = Password::new(password.map(|p| p.as_bytes()).unwrap_or_default());
+    let (id_a, id_b) = (Identity::new(SENDER_ID), Identity::new(RECEIVER_ID));
+
+    if sender {
+        Spake2::<Ed25519Group>::start_a(&pw, &id_a, &id_b)
+    } else {
+        Spake2::<Ed25519Group>::start_b(&pw, &id_a, &id_b)
+    }
+}
+
+// Get the session key from the message of the other side.
+// Different passwords give different keys, which the confirmation tags reveal.
+pub fn finish(state: PakeState, msg: &[u8]) -> Result<Vec<u8>> {
+    state.finish(msg).map_err(|e| anyhow!("Invalid key exchange message: {:?}", e))
+}
+
+// Tag to prove the knowledge of the session key without revealing it.
+// The transcript is the handshake data sent in plain text, which the tag also protects.
+pub fn confirm(key: &[u8], sender: bool, transcript: &[u8]) -> Vec<u8> {
+    let mut mac = new_mac(key);
+    mac.update(if sender { SENDER_ID } else { RECEIVER_ID });
+    mac.update(transcript);
+
+    mac.finalize().into_bytes().to_vec()
+}
+
+// Check the tag from the other side in constant time.
+pub fn verify(key: &[u8], sender: bool, transcript: &[u8], tag: &[u8]) -> bool {
+    let mut mac = new_mac(key);
+    mac.update(if sender { SENDER_ID } else { RECEIVER_ID });
+    mac.update(transcript);
+
+    mac.verify_slice(tag).is_ok()
+}
+
+fn new_mac(key: &[u8]) -> Hmac<Sha256> {
+    // HMAC accepts keys of any length.
+    Hmac::<Sha256>::new_from_slice(key).expect("HMAC key of any size")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn exchange(sender_pw: Option<&String>, receiver_pw: Option<&String>) -> (Vec<u8>, Vec<u8>) {
+        let (s_state, s_msg) = start(sender_pw, true);
+        let (r_state, r_msg) = start(receiver_pw, false);
+        assert_eq!((s_msg.len(), r_msg.len()), (MSG_SIZE, MSG_SIZE));
+
+        (finish(s_state, &r_msg).unwrap(), finish(r_state, &s_msg).unwrap())
+    }
+
+    #[test]
+    fn same_password_test() {
+        let pw = String::from("secret");
+        let (s_key, r_key) = exchange(Some(&pw), Some(&pw));
+        assert_eq!(s_key, r_key);
+        assert!(verify(&r_key, true, b"hello", &confirm(&s_key, true, b"hello")));
+        assert!(!verify(&r_key, false, b"hello", &confirm(&s_key, true, b"hello")));
+        assert!(!verify(&r_key, true, b"hellO", &confirm(&s_key, true, b"hello")));
+
+        let (s_key, r_key) = exchange(None, None);
+        assert!(verify(&s_key, false, b"", &confirm(&r_key, false, b"")));
+    }
+
+    #[test]
+    fn wrong_password_test() {
+        let (pw1, pw2) = (String::from("secret"), String::from("guess"));
+        let (s_key, r_key) = exchange(Some(&pw1), Some(&pw2));
+        assert!(!verify(&r_key, true, b"", &confirm(&s_key, true, b"")));
+
+        let (s_key, r_key) = exchange(Some(&pw1), None);
+        assert!(!verify(&s_key, false, b"", &confirm(&r_key, false, b"")));
+    }
+}
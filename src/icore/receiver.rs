@@ -1,49 +1,184 @@
 use anyhow::{anyhow, Result};
 use async_std::prelude::*;
 use async_std::fs::OpenOptions;
-use async_std::net::{TcpListener, TcpStream, UdpSocket};
-use std::path::PathBuf;
-use std::sync::mpsc;
-use std::time::Instant;
-use super::arg::{OverwriteStrategy, RecvArg};
-use super::currentfile::CurrentFile;
+use async_std::io::SeekFrom;
+use async_std::net::{TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use if_addrs::Interface;
+use std::net::SocketAddr;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+use super::archive::{Event, Parser};
+use super::arg::{ConnectMode, OverwriteStrategy, RecvArg};
+use super::code::{self, Rendezvous};
+use super::conn::Conn;
+use super::hello::{self, Hello, HELLO_SIZE};
+use super::currentfile::{self, CurrentFile};
 use super::instruction::{Instruction, Operation};
+use super::interrupt;
+use super::limit::{Limiter, Quota};
+use super::manifest::{self, Manifest};
 use super::message::{self, Message};
+use super::meta::{self, Meta};
+use super::net;
+use super::pake;
+use super::relay;
+use super::stats::{Meter, Stats};
+use super::streams::{self, Link, Target, Targets};
 use super::utils;
 
+// Unsafe names in an archive listed in the reply to its end, the rest are counted.
+const MAX_REFUSED: usize = 10;
+
 pub async fn launch(arg: RecvArg) -> Result<()> {
     log::info!("Start receiver function");
-    let tcp_socket = TcpListener::bind(("0.0.0.0", 0)).await?;
-    let tcp_port = tcp_socket.local_addr()?.port();
+    let password = arg.password.clone();
+    let local = local_hello(&arg);
+    if arg.daemon {
+        return daemon(arg, local).await;
+    }
+
+    let (mut stream, link) = match &arg.mode {
+        ConnectMode::Discover => {
+            let (stream, listeners) = discover(&arg, &local).await?;
+            (stream, Link::Accept(listeners))
+        },
+        ConnectMode::Listen(addr) => {
+            let listeners = vec![TcpListener::bind(addr.as_str()).await?];
+            message::send_msg(Message::Status(format!("Listening on {}", listeners[0].local_addr()?)));
+
+            let stream = listen_tcp_conn(&listeners, &local, &arg).await?;
+            (stream, Link::Accept(listeners))
+        },
+        ConnectMode::To(addr) => {
+            let (stream, socket) = connect_to(addr, &local, password.as_ref()).await?;
+            (stream, Link::Connect(socket))
+        },
+        ConnectMode::Relay(addr) => {
+            let stream = connect_relay(addr, &arg.code, &local, password.as_ref()).await?;
+            (stream, Link::Relay(addr.clone()))
+        },
+    };
+
+    start_recving(&mut stream, arg, &link).await?;
+
+    Ok(())
+}
+
+// Resume is only offered when it's on, so the sender knows it doesn't need to
+// wait for an offset before sending the content.
+// A daemon doesn't offer data connections, as they would come to the listener shared by all sessions.
+fn local_hello(arg: &RecvArg) -> Hello {
+    let mut local = Hello::local();
+    if !arg.resume {
+        local.caps &= !hello::CAP_RESUME;
+    }
+    if arg.daemon {
+        local.caps &= !hello::CAP_STREAMS;
+    }
+
+    local
+}
+
+// Listen on a TCP port and broadcast it with the nonce of the code,
+// so the sender can find this side and connect.
+// The listeners are returned as well for the data connections.
+async fn discover(arg: &RecvArg, local: &Hello) -> Result<(Conn, Vec<TcpListener>)> {
+    let ifaces = net::select_interfaces(&arg.interfaces)?;
+    let listeners = net::bind_tcp_listeners(&ifaces)?;
+    let tcp_port = listeners[0].local_addr()?.port();
     log::debug!("Listen on TCP port {}", tcp_port);
 
     let (tx, rx) = mpsc::channel();
-    let arg_code = arg.code.clone();
+    let rendezvous = code::rendezvous(&arg.code)?;
     async_std::task::spawn(async move {
-        if let Err(e) = broadcast_udp(tcp_port, arg_code, rx).await {
+        if let Err(e) = broadcast_udp(tcp_port, rendezvous, &ifaces, rx).await {
             message::send_msg(Message::Fatal(format!("UDP broadcast issue: {}", e)));
         }
     });
 
     // After the connection established, stop the broadcast.
-    let mut stream = listen_tcp_conn(&tcp_socket, arg.password.as_ref()).await?;
+    let stream = listen_tcp_conn(&listeners, local, arg).await?;
     tx.send(true)?;
 
-    start_recving(&mut stream, arg).await?;
+    Ok((stream, listeners))
+}
 
-    Ok(())
+// Connect to the sender on the known address, without discovery.
+// Try each address the host name resolves to.
+async fn connect_to(addr: &str, local: &Hello, password: Option<&String>) -> Result<(Conn, SocketAddr)> {
+    for socket in addr.to_socket_addrs().await? {
+        let tcp = match TcpStream::connect(socket).await {
+            Ok(tcp) => tcp,
+            Err(e) => {
+                message::send_msg(Message::Error(format!("Cannot connect to {}: {}", socket, e)));
+                continue;
+            }
+        };
+
+        let mut stream = Conn::new(tcp);
+        if authenticate(&mut stream, local, password).await? {
+            message::send_msg(Message::Status(format!("Connection established with {}\n", &socket)));
+            return Ok((stream, socket));
+        }
+
+        return Err(anyhow!("Connection refused: Invalid password"));
+    }
+
+    Err(anyhow!("Cannot connect to {}", addr))
+}
+
+// Meet the sender on the relay with the code.
+// The relay only passes the encrypted frames, the words of the code are part of the password.
+async fn connect_relay(addr: &str, code: &str, local: &Hello, password: Option<&String>) -> Result<Conn> {
+    let mut stream = relay::join(addr, relay::channel(code)?).await?;
+    let password = code::pake_password(code, password);
+    if !authenticate(&mut stream, local, Some(&password)).await? {
+        return Err(anyhow!("Connection refused: Invalid password"));
+    }
+
+    message::send_msg(Message::Status(format!("Connection established through relay {}\n", addr)));
+    Ok(stream)
 }
 
 // Send UDP broadcast 10 times unless signal received
 // local_port: TCP port of local machine(receiver)
-// target_port: UDP port of remote machine(sender) 
-async fn broadcast_udp(local_port: u16, target_port: u16, rx: mpsc::Receiver<bool>) -> Result<bool> {
+// rendezvous: UDP port of remote machine(sender) and the nonce it accepts
+// ifaces: interfaces to broadcast on, all of them if empty
+// IPv6 uses link-local multicast on every interface instead, if available.
+async fn broadcast_udp(local_port: u16, rendezvous: Rendezvous, ifaces: &[Interface], rx: mpsc::Receiver<bool>)
+    -> Result<bool> {
+
     let udp_socket = UdpSocket::bind("0.0.0.0:0").await?;
     udp_socket.set_broadcast(true)?;
+    let target_port = rendezvous.port;
+
+    let udp6 = match UdpSocket::bind("[::]:0").await {
+        Ok(s) => Some(s),
+        Err(e) => {
+            log::debug!("IPv6 discovery not available: {}", e);
+            None
+        }
+    };
+
+    let mut payload = rendezvous.nonce.to_vec();
+    payload.extend_from_slice(&u16::to_be_bytes(local_port));
 
     for _ in 0..10 {
-        udp_socket.send_to(&u16::to_be_bytes(local_port), ("255.255.255.255", target_port)).await?;
-        log::debug!("UDP broadcast sent to port {}", target_port);
+        if let Some(s) = &udp6 {
+            for target in net::multicast_targets(target_port, ifaces) {
+                if let Err(e) = s.send_to(&payload, target).await {
+                    log::debug!("Cannot send multicast to {}: {}", target, e);
+                }
+            }
+        }
+
+        for target in net::broadcast_targets(target_port, ifaces) {
+            udp_socket.send_to(&payload, target).await?;
+            log::debug!("UDP broadcast sent to {}", target);
+        }
         async_std::task::sleep(std::time::Duration::from_secs(5)).await;
 
         // Check message in channel
@@ -55,120 +190,470 @@ async fn broadcast_udp(local_port: u16, target_port: u16, rx: mpsc::Receiver<boo
     Err(anyhow!("No connection established"))
 }
 
-// Wait for tcp connection on the tcp socket and validate it.
-// TODO: terminate after time runs out
-async fn listen_tcp_conn(socket: &TcpListener, password: Option<&String>) -> Result<TcpStream> {
+// Wait for tcp connection on the tcp sockets and validate it.
+// Give up after the expire minutes. A client that stops answering in the middle
+// of the handshake is dropped after the timeout, so it cannot hold up the others.
+async fn listen_tcp_conn(listeners: &[TcpListener], local: &Hello, arg: &RecvArg) -> Result<Conn> {
+    let deadline = Instant::now() + Duration::from_secs(arg.expire as u64 * 60);
 
     loop {
-        let (mut stream, addr) = socket.accept().await?;
-        log::info!("Receive connection request from {}", &addr);
-
-        let ins = utils::recv_ins(&mut stream).await?;
-        match ins.operation {
-            Operation::Connect => {
-                match valiate_tcp_conn(&mut stream, &ins, password).await {
-                    Ok(true) => {
-                        utils::send_ins(&mut stream, 0, Operation::RequestSuccess, None).await?;
-                        message::send_msg(Message::Status(format!("Connection established with {}\n", &addr)));
-                        return Ok(stream);
-                    },
-                    Ok(false) => {
-                        let reply = format!("Invalid password");
-                        utils::send_ins(&mut stream, 0, Operation::RequestRefuse, Some(&reply)).await?;
-                        message::send_msg(Message::Status(format!("Connection refused: {}", reply)));
-                    }
-                    Err(e) => {
-                        let reply = format!("Get error when validating tcp connection: {}", e);
-                        utils::send_ins(&mut stream, 0, Operation::RequestError, Some(&reply)).await?;
-                        message::send_msg(Message::Status(reply));
-                    }
-                }
-            },
-            _ => { log::debug!("Unknown operation code when expecting connection request")},
+        let accept = async_std::future::timeout(deadline.saturating_duration_since(Instant::now()),
+            accept_any(listeners));
+        let (tcp, addr) = accept.await.map_err(|_| anyhow!("No connection in time"))??;
+        if let Some(stream) = handshake(tcp, addr, local, arg).await {
+            return Ok(stream);
         }
     }
 }
 
-// validate tcp connection, if both passwords are provided, check the password content.
-async fn valiate_tcp_conn(stream: &mut TcpStream, ins: &Instruction, password: Option<&String>)
-    -> Result<bool> {
+// Validate a new connection, None if it fails.
+async fn handshake(tcp: TcpStream, addr: SocketAddr, local: &Hello, arg: &RecvArg) -> Option<Conn> {
+    let addr = net::canonical(addr);
+    let mut stream = Conn::new(tcp);
+    log::info!("Receive connection request from {}", &addr);
 
-    match (ins.buffer, password.is_some()) {
-        (false, false) => Ok(true),
-        (true, true) => {
-            if compare_pass(stream, ins, password.unwrap()).await? {
-                Ok(true)
-            } else {
-                Ok(false)
-            }},
-        _ => Ok(false),
+    // With a code, its words are part of the password, so knowing the broadcast is not enough.
+    let password = match arg.mode {
+        ConnectMode::Discover => Some(code::pake_password(&arg.code, arg.password.as_ref())),
+        _ => arg.password.clone(),
+    };
+    let result = async_std::future::timeout(arg.timeout, authenticate(&mut stream, local, password.as_ref()))
+        .await.unwrap_or_else(|_| Err(anyhow!("No answer in {} seconds", arg.timeout.as_secs())));
+    match result {
+        Ok(true) => {
+            message::send_msg(Message::Status(format!("Connection established with {}\n", &addr)));
+            Some(stream)
+        },
+        Ok(false) => {
+            message::send_msg(Message::Status(format!("Connection from {} refused: Invalid password", &addr)));
+            None
+        },
+        Err(e) => {
+            message::send_msg(Message::Status(format!("Get error when validating tcp connection from {}: {}", &addr, e)));
+            None
+        }
     }
 }
 
-async fn compare_pass(stream: &mut TcpStream, ins: &Instruction, password: &String)
-    -> Result<bool> {
-    
-    let buf = utils::recv_content(stream, ins.length as usize).await?;
-    let req_pass = String::from_utf8(buf)?;
-    log::debug!("Local password: {}, remote password: {}", password, req_pass);
+// Keep accepting senders on the listening address until Ctrl-C, like the relay.
+// Each session has its own state and subdirectory, so they can overlap.
+// Nobody is there to answer prompts: all files are accepted, and existing ones renamed unless `--overwrite`.
+async fn daemon(arg: RecvArg, local: Hello) -> Result<()> {
+    let listener = match &arg.mode {
+        ConnectMode::Listen(addr) => TcpListener::bind(addr.as_str()).await?,
+        _ => return Err(anyhow!("The daemon needs an address to listen on")),
+    };
+    message::send_msg(Message::Status(format!("Daemon listening on {}, receiving into {:?}",
+        listener.local_addr()?, &arg.dir)));
+    if let Err(e) = interrupt::watch() {
+        log::info!("Cannot catch Ctrl-C: {}", e);
+    }
+
+    let mut arg = arg;
+    arg.yes = true;
+    if arg.overwrite == OverwriteStrategy::Ask {
+        arg.overwrite = OverwriteStrategy::Rename;
+    }
 
-    Ok(&req_pass == password)
+    let count = Arc::new(AtomicUsize::new(0));
+    let running = Arc::new(AtomicUsize::new(0));
+    loop {
+        let (tcp, addr) = match interrupt::or_interrupt(async { Ok(listener.accept().await?) }).await {
+            Ok(accepted) => accepted,
+            Err(_) if interrupt::is_set() => break,
+            Err(e) => return Err(e),
+        };
+
+        // A thread for each session, like the main thread of a single receiver.
+        // Dropping a file blocks to flush it, which would hold up the other sessions on a shared task thread.
+        let (arg, count, running) = (arg.clone(), count.clone(), Running::new(&running));
+        std::thread::spawn(move || async_std::task::block_on(async move {
+            let _running = running;
+            if let Err(e) = session(tcp, addr, arg, &local, &count).await {
+                message::send_msg(Message::Error(format!("Session failed: {}", e)));
+            }
+        }));
+    }
+
+    // The sessions abort on Ctrl-C as well, let them tell their senders.
+    while running.load(Ordering::SeqCst) > 0 {
+        async_std::task::sleep(Duration::from_millis(100)).await;
+    }
+    message::send_msg(Message::Status("Daemon stopped".to_string()));
+    message::send_msg(Message::Done);
+
+    Ok(())
 }
 
-async fn start_recving(stream: &mut TcpStream, arg:RecvArg) -> Result<()> {
-    let start_time = Instant::now();
+// Counts a session of the daemon as running until it ends, even by a panic,
+// so the daemon doesn't wait for it forever on Ctrl-C.
+struct Running(Arc<AtomicUsize>);
+
+impl Running {
+    fn new(running: &Arc<AtomicUsize>) -> Self {
+        running.fetch_add(1, Ordering::SeqCst);
+        Running(running.clone())
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// One session of the daemon. Its messages are labeled with its number and the sender's address.
+async fn session(tcp: TcpStream, addr: SocketAddr, arg: RecvArg, local: &Hello, count: &AtomicUsize) -> Result<()> {
+    let mut stream = match handshake(tcp, addr, local, &arg).await {
+        Some(stream) => stream,
+        None => return Ok(()),
+    };
+
+    let (number, dir) = session_dir(&arg.dir, &net::canonical(addr).ip().to_string(), count)?;
+    message::label_session(&format!("{} {}", number, net::canonical(addr)));
+    message::send_msg(Message::Status(format!("Session started, receiving into {:?}", &dir)));
+
+    start_recving(&mut stream, RecvArg { dir, ..arg }, &Link::Accept(Vec::new())).await
+}
+
+// Create a new subdirectory for the session, named by its number and the sender's address, like `3-10.0.0.5`.
+// Numbers taken by the sessions of an earlier run are skipped.
+fn session_dir(dir: &Path, host: &str, count: &AtomicUsize) -> Result<(usize, PathBuf)> {
+    let host = host.replace(':', "_");
+    loop {
+        let number = count.fetch_add(1, Ordering::SeqCst) + 1;
+        let path = dir.join(format!("{}-{}", number, host));
+        match std::fs::create_dir(&path) {
+            Ok(()) => return Ok((number, path)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(anyhow!("Cannot create {:?}: {}", path, e)),
+        }
+    }
+}
+
+// Accept a connection from whichever listener gets one first.
+async fn accept_any(listeners: &[TcpListener]) -> Result<(TcpStream, SocketAddr)> {
+    let accepts = listeners.iter().map(|l| Box::pin(l.accept()));
+    let (result, _, _) = futures::future::select_all(accepts).await;
+
+    Ok(result?)
+}
+
+// Verify the sender knows the same password with SPAKE2, without the password on the wire.
+// 1. Get the sender's hello and PAKE message, reply with the receiver's hello, message and confirmation tag.
+//    Refuse the sender if the protocol versions or capabilities don't match.
+// 2. Check the sender's confirmation tag. A wrong password gives a different key so the tag mismatches.
+//    The tags cover both hellos, so the capabilities cannot be changed on the way.
+// The rest of the session is encrypted with the PAKE session key.
+async fn authenticate(stream: &mut Conn, local: &Hello, password: Option<&String>) -> Result<bool> {
+    let ins = utils::recv_ins(stream).await?;
+    if ins.operation != Operation::Connect {
+        return Err(anyhow!("Unknown operation code when expecting connection request"));
+    }
+
+    // Anyone can connect before the password is proved, so nothing bigger than the request is read.
+    if ins.length as usize > HELLO_SIZE + pake::MSG_SIZE {
+        reply_refuse(stream, ins.id, "Invalid connection request").await?;
+        return Err(anyhow!("Connection request of {} bytes", ins.length));
+    }
+
+    let content = utils::recv_content(stream, ins.length as usize).await?;
+    let negotiated = Hello::decode(&content).and_then(|remote| Ok((remote, local.negotiate(&remote)?)));
+    let (remote, agreed) = match negotiated {
+        Ok(hellos) => hellos,
+        Err(e) => {
+            reply_refuse(stream, ins.id, &e.to_string()).await?;
+            return Err(anyhow!("Refused: {}", e));
+        }
+    };
+
+    let (state, msg) = pake::start(password, false);
+    let key = match pake::finish(state, &content[HELLO_SIZE..]) {
+        Ok(key) => key,
+        Err(e) => {
+            reply_error(stream, ins.id, &e.to_string()).await?;
+            return Err(e);
+        }
+    };
+
+    let transcript = [remote.encode(), local.encode()].concat();
+    let mut reply = local.encode().to_vec();
+    reply.extend_from_slice(&msg);
+    reply.extend_from_slice(&pake::confirm(&key, false, &transcript));
+    utils::send_ins_bytes(stream, ins.id, Operation::RequestSuccess, &reply).await?;
+
+    let ins = utils::recv_ins(stream).await?;
+    if ins.operation != Operation::KeyConfirm || ins.length as usize > pake::CONFIRM_SIZE {
+        return Err(anyhow!("Expecting key confirmation"));
+    }
+
+    let tag = utils::recv_content(stream, ins.length as usize).await?;
+    if !pake::verify(&key, true, &transcript, &tag) {
+        let reply = "Invalid password".to_string();
+        utils::send_ins(stream, ins.id, Operation::RequestRefuse, Some(&reply)).await?;
+        return Ok(false);
+    }
+
+    reply_success(stream, ins.id).await?;
+    stream.encrypt(&key, false)?;
+    stream.caps = agreed.caps;
+    log::debug!("Session encrypted with protocol {:?}", agreed);
+
+    Ok(true)
+}
+
+async fn start_recving(stream: &mut Conn, arg:RecvArg, link: &Link) -> Result<()> {
     let mut arg = arg;
     let mut current_file = CurrentFile::default();
-    let mut ins: Instruction;
+    let mut unpack: Option<Unpack> = None;
+    let mut targets: Option<Targets> = None;
+    let limiter = Limiter::new(arg.limit);
+    let stats = Stats::new();
+    let mut quota = Quota::new(arg.max_files, arg.max_size);
 
-    loop {
-        ins = utils::recv_ins(stream).await?;
+    // A daemon catches it once for all sessions.
+    if !arg.daemon {
+        if let Err(e) = interrupt::watch() {
+            log::info!("Cannot catch Ctrl-C: {}", e);
+        }
+    }
+    utils::keep_alive(stream, arg.timeout);
+
+    // Files are written to their parts, which a failed session leaves for resume or removes.
+    // If interrupted or anything goes wrong, the sender is told why, unless it aborted the session itself.
+    let mut aborted = false;
+    let result = async {
+        let mut ins: Instruction;
+        loop {
+            ins = interrupt::or_interrupt(utils::recv_ins(stream)).await?;
         
-        match ins.operation {
-            Operation::StartSendFile => recv_file_meta(stream, &ins, &mut current_file, &arg).await?,
-            Operation::SendFileContent => recv_file_content(stream, &ins, &mut current_file).await?,
-            Operation::EndSendFile => recv_file_end(stream, &ins, &mut current_file).await?,
-            Operation::StartSendDir => recv_dir(stream, &ins, &mut arg).await?,
-            Operation::EndSendDir => recv_dir_end(stream, &ins, &mut arg).await?,
-            Operation::SendMsg => recv_msg(stream, &ins).await?,
-            Operation::Disconnect => break,
-            _ => return Err(anyhow!("Unknown request instruction")),
+            match ins.operation {
+                Operation::OpenStreams => {
+                    recv_open_streams(stream, &ins, link, &mut targets, &limiter, &stats, arg.timeout).await?
+                },
+                Operation::SendManifest => recv_manifest(stream, &ins, &arg, &quota, &stats).await?,
+                Operation::StartSendFile => {
+                    let targets = targets.as_ref();
+                    recv_file_meta(stream, &ins, &mut current_file, &arg, targets, &mut quota, &stats).await?
+                },
+                Operation::SendFileContent => {
+                    recv_file_content(stream, &ins, &mut current_file, &limiter, &stats).await?
+                },
+                Operation::EndSendFile => {
+                    recv_file_end(stream, &ins, &mut current_file, &arg, targets.as_ref(), &stats).await?
+                },
+                Operation::StartSendDir => { recv_dir(stream, &ins, &mut arg).await?; },
+                Operation::EndSendDir => recv_dir_end(stream, &ins, &mut arg).await?,
+                Operation::StartArchive => {
+                    let parent = arg.dir.clone();
+                    if recv_dir(stream, &ins, &mut arg).await? {
+                        let (limiter, stats) = (limiter.clone(), stats.clone());
+                        unpack = Some(Unpack { parent, limiter, stats, ..Default::default() });
+                    }
+                },
+                Operation::ArchiveData => match unpack.as_mut() {
+                    Some(u) => recv_archive_data(stream, &ins, u, &mut arg, &mut quota).await?,
+                    None => return Err(anyhow!("Archive data without archive")),
+                },
+                Operation::EndArchive => match unpack.take() {
+                    Some(u) => recv_archive_end(stream, &ins, u, &mut arg).await?,
+                    None => return Err(anyhow!("Archive end without archive")),
+                },
+                Operation::AbortFile => {
+                    recv_file_abort(stream, &ins, &mut current_file, &arg, targets.as_ref(), &stats).await?
+                },
+                Operation::AbortDir => recv_dir_abort(stream, &ins, unpack.take(), &mut arg).await?,
+                Operation::AbortSession => {
+                    aborted = true;
+                    return Err(anyhow!("Sender aborted the session: {}", utils::recv_reason(stream, &ins).await?));
+                },
+                Operation::SendMsg => recv_msg(stream, &ins).await?,
+                Operation::Disconnect => break,
+                _ => return Err(anyhow!("Unknown request instruction")),
+            }
         }
+
+        shutdown(stream, ins.id, &stats).await
+    }.await;
+
+    if let Err(e) = &result {
+        if let Some(targets) = &targets {
+            streams::abort(targets, None);
+        }
+        if !aborted {
+            utils::abort_session(stream, 0, &e.to_string()).await;
+        }
+        discard_parts(&current_file, unpack.as_ref(), arg.resume);
+        message::send_msg(Message::Status(stats.summary("Received")));
     }
 
-    shutdown(stream, ins.id, &start_time).await
+    result
 }
 
-async fn recv_dir(stream: &mut TcpStream, ins: &Instruction, arg: &mut RecvArg) -> Result<()> {
-    let dir_name_buf = utils::recv_content(stream, ins.length as usize).await?;
-    let dir_name = String::from_utf8(dir_name_buf)?;
-    message::send_msg(Message::Status(format!("Start receiving directory: {:?}", dir_name)));
+// A failed session leaves the part of the current file for `--resume`, otherwise it's removed.
+// A part in an archive cannot be resumed, so it's always removed,
+// and the files waiting for the checksum of the archive don't count as received any more.
+fn discard_parts(file: &CurrentFile, unpack: Option<&Unpack>, resume: bool) {
+    let mut parts = Vec::new();
+    match &file.part {
+        Some(part) if resume => log::info!("Keep {:?} for resume", part),
+        Some(part) => parts.push(part),
+        None => (),
+    }
+    if let Some(unpack) = unpack {
+        parts.extend(unpack.file.part.as_ref());
+        parts.extend(unpack.written.iter().filter_map(|f| f.part.as_ref()));
+        unpack.stats.undo(unpack.written.len() as u64);
+    }
+
+    for part in parts {
+        if let Err(e) = std::fs::remove_file(part) {
+            log::info!("Cannot remove {:?}: {}", part, e);
+        }
+    }
+}
+
+// Show what the sender is going to send, and let the user accept all, reject all
+// or pick the top level items, unless `--yes`. More than the free space or the quota is refused without asking.
+// The reply has the indices of the rejected items, which the sender leaves out.
+async fn recv_manifest(stream: &mut Conn, ins: &Instruction, arg: &RecvArg, quota: &Quota, stats: &Stats)
+    -> Result<()> {
+    let buf = utils::recv_content(stream, ins.length as usize).await?;
+    let manifest = match Manifest::decode(&buf) {
+        Ok(manifest) => manifest,
+        Err(e) => return reply_error(stream, ins.id, &format!("Cannot read manifest: {}", e)).await,
+    };
+    show_manifest(&manifest, arg.daemon);
+
+    let fits = check_space(&arg.dir, manifest.bytes).and_then(|_| quota.check(manifest.files, manifest.bytes));
+    if let Err(e) = fits {
+        return reply_refuse(stream, ins.id, &e.to_string()).await;
+    }
+
+    let rejected = match arg.yes {
+        true => Vec::new(),
+        false => match review(&manifest) {
+            Some(rejected) => rejected,
+            None => return reply_refuse(stream, ins.id, "Files rejected by receiver").await,
+        },
+    };
+    stats.expect(manifest.pick(&rejected));
+
+    let detail: Vec<String> = rejected.iter().map(|i| i.to_string()).collect();
+    reply_success_with(stream, ins.id, &detail.join(",")).await
+}
+
+// Only the first lines of a large manifest are shown, the top level items are numbered for picking.
+// Only the totals in `brief`, for the log of a daemon.
+fn show_manifest(manifest: &Manifest, brief: bool) {
+    const MAX_LINES: usize = 30;
+    let mut lines = vec![format!("Incoming: {} files in {} directories, {}",
+        manifest.files, manifest.dirs, currentfile::human_read_size(manifest.bytes))];
+    if brief {
+        return message::send_msg(Message::Status(lines.remove(0)));
+    }
+
+    let mut top = 0;
+    for item in manifest.items.iter().take(MAX_LINES) {
+        let indent = "   ".repeat(item.depth as usize + 1);
+        let number = match item.depth {
+            0 => { top += 1; format!("{}. ", top) },
+            _ => String::new(),
+        };
+        lines.push(format!("{}{}{}", indent, number, describe_item(item)));
+    }
+    if manifest.items.len() > MAX_LINES {
+        lines.push(format!("   ... and {} more", manifest.items.len() - MAX_LINES));
+    }
+
+    message::send_msg(Message::Status(lines.join("\n")));
+}
+
+fn describe_item(item: &manifest::Item) -> String {
+    let name = meta::display_name(&item.name);
+    match item.dir {
+        true => format!("\"{}/\"  {} files, {}", name, item.files, currentfile::human_read_size(item.bytes)),
+        false => format!("\"{}\"  {}", name, currentfile::human_read_size(item.bytes)),
+    }
+}
+
+// Return the indices of the rejected top level items, or None if all of them are rejected.
+fn review(manifest: &Manifest) -> Option<Vec<usize>> {
+    loop {
+        let input = message::send_prompt(Message::Prompt(
+            "Please choose: accept all(a) | reject all(r) | pick(p): ".to_string()));
+        match input.trim() {
+            "a" | "A" => return Some(Vec::new()),
+            "r" | "R" => return None,
+            "p" | "P" => break,
+            _ => message::send_msg(Message::Status("Unknown choice".to_string())),
+        }
+    }
+
+    let mut rejected = Vec::new();
+    for (i, item) in manifest.top_items().enumerate() {
+        loop {
+            let input = message::send_prompt(Message::Prompt(
+                format!("Accept {}. {}? yes(y) | no(n): ", i + 1, describe_item(item))));
+            match input.trim() {
+                "y" | "Y" => break,
+                "n" | "N" => {
+                    rejected.push(i);
+                    break;
+                },
+                _ => message::send_msg(Message::Status("Unknown choice".to_string())),
+            }
+        }
+    }
+
+    match rejected.len() < manifest.top_items().count() {
+        true => Some(rejected),
+        false => None,
+    }
+}
+
+// Return whether the directory is entered, i.e. `arg.dir` is changed to it.
+async fn recv_dir(stream: &mut Conn, ins: &Instruction, arg: &mut RecvArg) -> Result<bool> {
+    let meta_buf = utils::recv_content(stream, ins.length as usize).await?;
+    let dir_name = match Meta::decode(&meta_buf) {
+        Ok(meta) => meta.name,
+        Err(e) => {
+            reply_error(stream, ins.id, &format!("Cannot read directory meta info: {}", e)).await?;
+            return Ok(false);
+        }
+    };
+    message::send_msg(Message::Status(format!("Start receiving directory: {:?}", meta::display_name(&dir_name))));
+
+    if let Err(e) = meta::check_name(&dir_name) {
+        reply_refuse(stream, ins.id, &format!("Directory refused: {}", e)).await?;
+        return Ok(false);
+    }
 
     let (child_path, needs_create) = match get_valid_path(&dir_name, arg) {
         Some((path, need)) => (path, need),
         None => {
             reply_refuse(stream, ins.id, "Directory refused: user chose skip").await?;
-            return Ok(());
+            return Ok(false);
         }
     };
 
     // Only if the overwrite strategy is `rename`, a new directory needs to be created.
     if needs_create && !create_dir(&child_path) {
-        let detail = format!("Cannot create directory on receiver");
+        let detail = "Cannot create directory on receiver".to_string();
         reply_error(stream, ins.id, &detail).await?;
 
-        return Ok(());
+        return Ok(false);
     }
 
     arg.dir = child_path;
     log::debug!("Current working dir is: {:?}", &arg.dir);
     reply_success(stream, ins.id).await?;
 
-    Ok(())
+    Ok(true)
 }
 
 fn create_dir(path: &PathBuf) -> bool {
-    if let Err(e) = std::fs::create_dir(&path) {
+    if let Err(e) = std::fs::create_dir(path) {
         message::send_msg(Message::Error(format!("Create dir failed: {}", e)));
         return false;
     }
@@ -176,8 +661,30 @@ fn create_dir(path: &PathBuf) -> bool {
     true
 }
 
-async fn recv_dir_end(stream: &mut TcpStream, ins: &Instruction, arg:&mut RecvArg) -> Result<()> {
-    let current = arg.dir.file_name().unwrap();
+// The sender cannot go on with the directory, like when it cannot read it.
+// Go back to the parent, and for an archive remove the files not finished yet.
+async fn recv_dir_abort(stream: &mut Conn, ins: &Instruction, unpack: Option<Unpack>, arg: &mut RecvArg)
+    -> Result<()> {
+    let reason = utils::recv_reason(stream, ins).await?;
+    let current = meta::display_name(arg.dir.file_name().unwrap_or_default());
+    message::send_msg(Message::Error(format!("Sender aborted directory {:?}: {}", current, reason)));
+
+    match unpack {
+        Some(unpack) => {
+            discard_parts(&CurrentFile::default(), Some(&unpack), false);
+            arg.dir = unpack.parent;
+        },
+        None => {
+            arg.dir.pop();
+        },
+    }
+    log::debug!("Current working dir: {:?}", &arg.dir);
+
+    Ok(())
+}
+
+async fn recv_dir_end(stream: &mut Conn, ins: &Instruction, arg:&mut RecvArg) -> Result<()> {
+    let current = meta::display_name(arg.dir.file_name().unwrap_or_default());
     message::send_msg(Message::Status(format!("Finish receiving directory: {:?}", current)));
     arg.dir.pop();
     log::debug!("Current working dir: {:?}", &arg.dir);
@@ -186,11 +693,209 @@ async fn recv_dir_end(stream: &mut TcpStream, ins: &Instruction, arg:&mut RecvAr
     Ok(())
 }
 
+// Open the data connections the sender asks for, the same way as the control connection.
+// The ranges of files from them are written by separate tasks.
+async fn recv_open_streams(stream: &mut Conn, ins: &Instruction, link: &Link, targets: &mut Option<Targets>,
+    limiter: &Limiter, stats: &Stats, timeout: Duration) -> Result<()> {
+    let buf = utils::recv_content(stream, ins.length as usize).await?;
+    let count = buf.first().copied().unwrap_or(0);
+    if stream.caps & hello::CAP_STREAMS == 0 {
+        return reply_refuse(stream, ins.id, "Multiple streams not supported").await;
+    }
+    if targets.is_some() || count == 0 || count > streams::MAX_STREAMS {
+        return reply_refuse(stream, ins.id, "Invalid number of streams").await;
+    }
+
+    reply_success(stream, ins.id).await?;
+    let conns = streams::open(link, stream, count, false, timeout).await?;
+    let opened = Targets::default();
+    streams::spawn_readers(conns, &opened, limiter, stats);
+    *targets = Some(opened);
+    message::send_msg(Message::Status(format!("Opened {} data connections", count)));
+
+    Ok(())
+}
+
+// State of the archive being unpacked.
+#[derive(Default)]
+struct Unpack {
+    parser: Parser,
+    hasher: blake3::Hasher,     // checksum of the whole archive stream.
+    parent: PathBuf,            // working dir to go back to after the archive.
+    file: CurrentFile,          // file being written, without fd if skipped.
+    written: Vec<CurrentFile>,  // parts waiting for the checksum of the archive.
+    refused: Vec<String>,       // entries with unsafe names, reported in the reply to the end.
+    skip: usize,                // depth inside a skipped directory.
+    limiter: Limiter,
+    meter: Meter,
+    stats: Stats,
+    files: u64,
+    bytes: u64,
+}
+
+impl Unpack {
+    fn refuse(&mut self, name: &OsStr, reason: anyhow::Error) {
+        let entry = format!("\"{}\": {}", meta::display_name(name), reason);
+        message::send_msg(Message::Error(format!("Refused {}", &entry)));
+        self.refused.push(entry);
+    }
+}
+
+// Unpack a piece of the archive on the fly.
+// Entries are placed with the same overwrite rules as single files and dirs.
+// An invalid archive stops the session, as the rest of the stream cannot be trusted.
+async fn recv_archive_data(stream: &mut Conn, ins: &Instruction, unpack: &mut Unpack, arg: &mut RecvArg,
+    quota: &mut Quota) -> Result<()> {
+    unpack.limiter.take(ins.length as usize).await;
+    let chunk = utils::recv_chunk(stream, ins).await?;
+    unpack.hasher.update(&chunk);
+
+    for event in unpack.parser.feed(&chunk)? {
+        unpack_event(event, unpack, arg, quota).await?;
+    }
+
+    unpack.meter.update(unpack.bytes);
+    message::send_msg(Message::Progress(format!("Archive: {} files\t\tProgress: {}, {}\t\t{}",
+        unpack.files, currentfile::human_read_size(unpack.bytes), unpack.meter.describe(None), unpack.stats.progress())));
+
+    Ok(())
+}
+
+// A file which doesn't fit is skipped like a refused one, the rest of the archive goes on.
+async fn unpack_event(event: Event, unpack: &mut Unpack, arg: &mut RecvArg, quota: &mut Quota) -> Result<()> {
+    match event {
+        Event::Dir(_) if unpack.skip > 0 => unpack.skip += 1,
+        Event::Dir(meta) => {
+            let valid = match meta::check_name(&meta.name) {
+                Ok(()) => get_valid_path(&meta.name, arg),
+                Err(e) => {
+                    unpack.refuse(&meta.name, e);
+                    None
+                }
+            };
+
+            match valid {
+                Some((path, needs_create)) if !needs_create || create_dir(&path) => arg.dir = path,
+                _ => {
+                    message::send_msg(Message::Status(format!("Skip directory {:?}", meta::display_name(&meta.name))));
+                    unpack.skip = 1;
+                }
+            }
+        },
+        Event::Up if unpack.skip > 0 => unpack.skip -= 1,
+        Event::Up => {
+            arg.dir.pop();
+        },
+        Event::File(meta) => {
+            unpack.file = CurrentFile { name: meta.name, size: meta.size.unwrap_or_default(), attr: meta.attr,
+                ..Default::default() };
+            if unpack.skip > 0 {
+                return Ok(());
+            }
+
+            if let Err(e) = meta::check_name(&unpack.file.name) {
+                let name = unpack.file.name.clone();
+                unpack.refuse(&name, e);
+                return Ok(());
+            }
+
+            let path = match get_valid_path(&unpack.file.name, arg) {
+                Some((path, _)) => path,
+                None => return Ok(()),
+            };
+
+            let size = unpack.file.size;
+            if let Err(e) = check_space(&arg.dir, size).and_then(|_| quota.take(1, size)) {
+                message::send_msg(Message::Error(format!("Skip file {:?}: {}", meta::display_name(&unpack.file.name), e)));
+                return Ok(());
+            }
+
+            let part = part_path(&path);
+            match OpenOptions::new().write(true).create(true).truncate(true).open(&part).await {
+                Ok(fd) => {
+                    unpack.file.fd = Some(fd);
+                    unpack.file.part = Some(part);
+                },
+                Err(e) => message::send_msg(Message::Error(format!("Cannot create file {:?}: {}", path, e))),
+            }
+            unpack.file.path = path;
+        },
+        Event::Data(data) => {
+            if let Some(fd) = unpack.file.fd.as_mut() {
+                fd.write_all(&data).await?;
+            }
+            unpack.bytes += data.len() as u64;
+            unpack.stats.add_bytes(data.len() as u64);
+        },
+        Event::FileEnd => {
+            let mut finished = std::mem::take(&mut unpack.file);
+            unpack.files += 1;
+            match finished.fd.take() {
+                Some(mut fd) => {
+                    fd.flush().await?;
+                    fd.sync_all().await?;
+                    unpack.stats.file_done();
+                    unpack.written.push(finished);
+                },
+                None => unpack.stats.skip(1, 0),
+            }
+        },
+    }
+
+    Ok(())
+}
+
+// Check the archive is complete and the checksum of the whole stream matches,
+// then go back to the working dir before the archive.
+async fn recv_archive_end(stream: &mut Conn, ins: &Instruction, unpack: Unpack, arg: &mut RecvArg) -> Result<()> {
+    let digest = String::from_utf8(utils::recv_content(stream, ins.length as usize).await?)?;
+    let current = meta::display_name(arg.dir.file_name().unwrap_or_default());
+    arg.dir = unpack.parent.clone();
+    message::send_msg(Message::FileEnd);
+    log::debug!("Current working dir: {:?}", &arg.dir);
+
+    let error = match unpack.parser.is_complete() {
+        false => Some("Archive incomplete"),
+        true if digest != unpack.hasher.finalize().to_hex().to_string() => Some("Checksum mismatch for archive"),
+        true => None,
+    };
+    if let Some(error) = error {
+        discard_parts(&CurrentFile::default(), Some(&unpack), false);
+        return reply_error(stream, ins.id, error).await;
+    }
+
+    for mut file in unpack.written {
+        if let Err(e) = commit_part(&mut file).await {
+            message::send_msg(Message::Error(format!("Cannot save file {:?}: {}", meta::display_name(&file.name), e)));
+            continue;
+        }
+        if let Err(e) = file.attr.apply(&file.path, &arg.keep) {
+            message::send_msg(Message::Error(format!("Cannot set attributes of {:?}: {}",
+                meta::display_name(&file.name), e)));
+        }
+    }
+
+    message::send_msg(Message::Status(format!("Finish receiving directory: {:?}, {} files, {}",
+        current, unpack.files, currentfile::human_read_size(unpack.bytes))));
+    if unpack.refused.is_empty() {
+        return reply_success(stream, ins.id).await;
+    }
+
+    // The sender can't know which entries were left out otherwise.
+    let mut detail = format!("Refused unsafe names: {}", unpack.refused.iter().take(MAX_REFUSED)
+        .cloned().collect::<Vec<_>>().join(", "));
+    if unpack.refused.len() > MAX_REFUSED {
+        detail.push_str(&format!(" and {} more", unpack.refused.len() - MAX_REFUSED));
+    }
+    reply_success_with(stream, ins.id, &detail).await
+}
+
 // Read file meta info from sender and prepare the file descriptor.
+// A file which doesn't fit in the free space or the quota is refused before anything is written.
 // If file name already existed, perform according to the overwrite strategy.
-// TODO: check available disk space.
-async fn recv_file_meta(stream: &mut TcpStream, ins: &Instruction, 
-    file: &mut CurrentFile, arg: &RecvArg) -> Result<()> {
+// With data connections, the file is also open for the ranges from them.
+async fn recv_file_meta(stream: &mut Conn, ins: &Instruction, file: &mut CurrentFile, arg: &RecvArg,
+    targets: Option<&Targets>, quota: &mut Quota, stats: &Stats) -> Result<()> {
     
     // If the previous file is still transmitting, refuse current file and print error message.
     // Return OK so the loop in parent function will continue.
@@ -200,22 +905,56 @@ async fn recv_file_meta(stream: &mut TcpStream, ins: &Instruction,
     }
 
     let meta = utils::recv_content(stream, ins.length as usize).await?;
-    let (size, name) = match CurrentFile::meta_from_string(&String::from_utf8(meta)?) {
-        Ok((s, n)) => (s, n),
-        Err(_) => {
+    let (size, name, attr) = match Meta::decode(&meta) {
+        Ok(Meta { size: Some(size), name, attr }) => (size, name, attr),
+        _ => {
             reply_error(stream, ins.id, "Cannot read file meta info").await?;
             return Ok(());
         },
     };
 
-    log::debug!("File name: {}, size: {}", &name, size);
+    log::debug!("File name: {:?}, size: {}", &name, size);
+
+    // The name goes under the destination directory, anything leading out of it is refused.
+    if let Err(e) = meta::check_name(&name) {
+        log::info!("Refuse unsafe file name {:?}: {}", &name, e);
+        stats.skip(1, size);
+        return reply_refuse(stream, ins.id, &format!("File refused: {}", e)).await;
+    }
+
+    // A partial file is resumed directly without going through the overwrite strategy.
+    // The offset is sent back so the sender knows where to continue.
+    let resume = stream.caps & hello::CAP_RESUME != 0;
+    let partial = get_partial_file(&name, size, arg).filter(|_| resume);
+    let needed = size - partial.as_ref().map_or(0, |(_, offset)| *offset);
+    if let Err(e) = check_space(&arg.dir, needed).and_then(|_| quota.check(1, needed)) {
+        stats.skip(1, size);
+        return reply_refuse(stream, ins.id, &format!("File refused: {}", e)).await;
+    }
+
+    if let Some((path, offset)) = partial {
+        quota.take(1, needed)?;
+        prepare_file(path, size, offset, file).await?;
+        stats.skip(0, offset);
+        file.attr = attr;
+        open_target(file, ins.id, targets)?;
+        reply_success_with(stream, ins.id, &offset.to_string()).await?;
+        message::send_msg(Message::Status(format!("Resume file {:?} at {} bytes", meta::display_name(&name), offset)));
+        log::debug!("Prepared resumed file: {:?}", file);
+        return Ok(());
+    }
+
     match get_valid_path(&name, arg) {
         Some((path, _)) => {
-            prepare_file(path, size, file).await?;
+            quota.take(1, needed)?;
+            prepare_file(path, size, 0, file).await?;
+            file.attr = attr;
+            open_target(file, ins.id, targets)?;
             reply_success(stream, ins.id).await?;
             log::debug!("Prepared file: {:?}", file);
         },
         None => {
+            stats.skip(1, size);
             reply_refuse(stream, ins.id, "File refused: user chose skip").await?;
             return Ok(());
         }
@@ -224,31 +963,119 @@ async fn recv_file_meta(stream: &mut TcpStream, ins: &Instruction,
     Ok(())
 }
 
-async fn recv_file_content(stream: &mut TcpStream, ins: &Instruction, file: &mut CurrentFile) -> Result<()> {
-    let content_buf = utils::recv_content(stream, ins.length as usize).await?;
+fn open_target(file: &mut CurrentFile, id: u16, targets: Option<&Targets>) -> Result<()> {
+    file.id = id;
+    if let Some(targets) = targets {
+        let fd = std::fs::OpenOptions::new().write(true).open(file.write_path())?;
+        let target = Target::new(fd, file.name.clone(), file.size);
+        targets.lock().unwrap().insert(id, std::sync::Arc::new(target));
+    }
+
+    Ok(())
+}
+
+// The sender doesn't wait for the reply to the file meta info,
+// so the content of a refused file comes until the sender sees the refusal, and is dropped.
+// Reading slower than the limit makes the sender slow down as well.
+async fn recv_file_content(stream: &mut Conn, ins: &Instruction, file: &mut CurrentFile, limiter: &Limiter,
+    stats: &Stats) -> Result<()> {
+    limiter.take(ins.length as usize).await;
+    if file.fd.is_none() {
+        utils::recv_content(stream, ins.length as usize).await?;
+        return Ok(());
+    }
+
+    let content_buf = utils::recv_chunk(stream, ins).await?;
     let mut fd = file.must_get_fd()?;
 
     fd.write_all(&content_buf).await?;
-    file.transmitted += ins.length as u64;
-    message::send_msg(Message::Progress(file.get_progress()));
+    file.hasher.update(&content_buf);
+    file.advance(content_buf.len() as u64);
+    stats.add_bytes(content_buf.len() as u64);
+    message::send_msg(Message::Progress(format!("{}\t\t{}", file.get_progress(), stats.progress())));
 
     Ok(())
 }
 
-async fn recv_file_end(stream: &mut TcpStream, ins: &Instruction, file: &mut CurrentFile) -> Result<()> {
+// The sender cannot go on with the current file, like when it cannot read it any more.
+// The part is kept for `--resume`, otherwise removed, and the next file comes as usual.
+async fn recv_file_abort(stream: &mut Conn, ins: &Instruction, file: &mut CurrentFile, arg: &RecvArg,
+    targets: Option<&Targets>, stats: &Stats) -> Result<()> {
+    let reason = utils::recv_reason(stream, ins).await?;
     if file.fd.is_none() {
-        return Err(anyhow!("File not opened"));
+        return Ok(());
     }
 
-    // Reset the current file when receiving the end file command.
+    let written = targets.map_or(0, |t| streams::abort(t, Some(file.id)));
+    stats.skip(1, file.size.saturating_sub(file.transmitted + written));
+    message::send_msg(Message::FileEnd);
+    message::send_msg(Message::Error(format!("Sender aborted file {:?}: {}", meta::display_name(&file.name), reason)));
+    discard_parts(file, None, arg.resume);
     *file = CurrentFile::default();
+
+    Ok(())
+}
+
+// Compare the checksum from sender with the local one before finishing the file.
+// A mismatched file is removed so it can be sent again from the start.
+// Then the attributes from the sender are applied, after the file is closed.
+// Ranges from data connections come in any order, so the checksum is taken from the file
+// once all of them are written.
+async fn recv_file_end(stream: &mut Conn, ins: &Instruction, file: &mut CurrentFile, arg: &RecvArg,
+    targets: Option<&Targets>, stats: &Stats) -> Result<()> {
+    let digest = if ins.buffer {
+        String::from_utf8(utils::recv_content(stream, ins.length as usize).await?)?
+    } else {
+        String::new()
+    };
+
+    // The refusal was already replied to the meta info.
+    if file.fd.is_none() {
+        let detail = String::from("File not received");
+        return utils::send_ins(stream, ins.id, Operation::RequestRefuse, Some(&detail)).await;
+    }
+
+    // Small files still come on the control connection.
+    if let Some(targets) = targets {
+        if file.transmitted < file.size {
+            stream.alive(streams::wait(targets, file.id, file.size - file.transmitted)).await??;
+            file.hasher = blake3::Hasher::new();
+            file.hash_existing(file.size).await?;
+            file.transmitted = file.size;
+        }
+        targets.lock().unwrap().remove(&file.id);
+    }
+
+    if !digest.is_empty() && digest != file.digest() {
+        let path = file.write_path().to_path_buf();
+        let detail = format!("Checksum mismatch for file {:?}", meta::display_name(&file.name));
+        *file = CurrentFile::default();
+        message::send_msg(Message::FileEnd);
+        std::fs::remove_file(&path)?;
+        reply_error(stream, ins.id, &detail).await?;
+
+        return Ok(());
+    }
+
+    // Reset the current file when receiving the end file command.
+    // The part is moved in place only now, after the checksum passed.
+    let mut finished = std::mem::take(file);
     message::send_msg(Message::FileEnd);
+    if let Err(e) = commit_part(&mut finished).await {
+        let _ = std::fs::remove_file(finished.write_path());
+        let detail = format!("Cannot save file {:?}: {}", meta::display_name(&finished.name), e);
+        return reply_error(stream, ins.id, &detail).await;
+    }
+    if let Err(e) = finished.attr.apply(&finished.path, &arg.keep) {
+        message::send_msg(Message::Error(format!("Cannot set attributes of {:?}: {}", meta::display_name(&finished.name), e)));
+    }
     utils::send_ins(stream, ins.id, Operation::RequestSuccess, None).await?;
+    stats.file_done();
 
     Ok(())
 }
 
-async fn recv_msg(stream: &mut TcpStream, ins: &Instruction) -> Result<()> {
+async fn recv_msg(stream: &mut Conn, ins: &Instruction) -> Result<()> {
     let msg_buf = utils::recv_content(stream, ins.length as usize).await?;
     let msg = String::from_utf8(msg_buf)?;
     message::send_msg(Message::Status(format!("\nMessage received: \"{}\"", &msg)));
@@ -257,9 +1084,22 @@ async fn recv_msg(stream: &mut TcpStream, ins: &Instruction) -> Result<()> {
     Ok(())
 }
 
-fn get_valid_path(name: &String, arg: &RecvArg) -> Option<(PathBuf, bool)> {
+// Refuse what doesn't fit before anything is written, instead of failing halfway with a full disk.
+fn check_space(dir: &Path, needed: u64) -> Result<()> {
+    match fs2::available_space(dir) {
+        Ok(free) if free < needed => Err(anyhow!("Not enough space on receiver: {} needed, {} free",
+            currentfile::human_read_size(needed), currentfile::human_read_size(free))),
+        Ok(_) => Ok(()),
+        Err(e) => {
+            log::info!("Cannot get the free space of {:?}: {}", dir, e);
+            Ok(())
+        }
+    }
+}
+
+fn get_valid_path(name: &OsStr, arg: &RecvArg) -> Option<(PathBuf, bool)> {
     let mut path = PathBuf::new();
-    let mut overwrite = arg.overwrite.clone();
+    let mut overwrite = arg.overwrite;
     let mut i = 0u16;
     let mut renamed = false;
 
@@ -290,8 +1130,10 @@ fn get_valid_path(name: &String, arg: &RecvArg) -> Option<(PathBuf, bool)> {
                 break;
             },
             OverwriteStrategy::Rename => {
+                let mut new_name = OsString::from(format!("{}_", i));
+                new_name.push(name);
                 path.pop();
-                path.push(format!("{}_{}", i, name));
+                path.push(new_name);
                 i += 1;     // Assume u16 is more than enough to try.
                 renamed = true;
             },
@@ -310,36 +1152,97 @@ fn get_valid_path(name: &String, arg: &RecvArg) -> Option<(PathBuf, bool)> {
     Some((path, !existed || renamed))
 }
 
-async fn prepare_file(path: PathBuf, size: u64, file: &mut CurrentFile) -> Result<()> {
-    log::debug!("Creating file: {:?}", &path);
-    let filename = String::from(path.file_name().unwrap().to_str().unwrap());
-    let path_str = path.to_str().unwrap();
-    let fd = OpenOptions::new().write(true).create(true).open(path_str).await?;
+// In resume mode, the part of the file left by an interrupted transmission is continued,
+// if it's not larger than the incoming one. Return the final path and the size of the part.
+fn get_partial_file(name: &OsStr, size: u64, arg: &RecvArg) -> Option<(PathBuf, u64)> {
+    if !arg.resume {
+        return None;
+    }
+
+    let path = arg.dir.join(name);
+    let meta = std::fs::metadata(part_path(&path)).ok()?;
+    let len = meta.len();
+
+    if meta.is_file() && len > 0 && len <= size {
+        Some((path, len))
+    } else {
+        None
+    }
+}
+
+// The hidden file next to `path` which the content is written to, like `.a.txt.part`.
+fn part_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".part");
+
+    path.with_file_name(name)
+}
+
+// Flush the part to the disk, then move it in place of the final name.
+async fn commit_part(file: &mut CurrentFile) -> Result<()> {
+    if let Some(mut fd) = file.fd.take() {
+        fd.flush().await?;
+        fd.sync_all().await?;
+    }
+
+    if let Some(part) = file.part.take() {
+        async_std::fs::rename(&part, &file.path).await?;
+    }
+
+    Ok(())
+}
+
+// Open the part of the file for writing, the final name is only used when it's verified.
+// A non-zero offset means the file is resumed, so the existing content before the offset is kept.
+async fn prepare_file(path: PathBuf, size: u64, offset: u64, file: &mut CurrentFile) -> Result<()> {
+    let part = part_path(&path);
+    log::debug!("Creating file: {:?}", &part);
+    let filename = path.file_name().unwrap_or_default().to_os_string();
+    let mut fd = OpenOptions::new().write(true).create(true).truncate(offset == 0).open(&part).await?;
+    if offset > 0 {
+        fd.seek(SeekFrom::Start(offset)).await?;
+    }
 
     file.path = path;
+    file.part = Some(part);
     file.name = filename;
     file.size = size;
+    file.transmitted = offset;
+    file.offset = offset;
     file.fd = Some(fd);
+    file.start();
+
+    // The resumed part is not transmitted again but still counts in the checksum.
+    if offset > 0 {
+        file.hash_existing(offset).await?;
+    }
 
     Ok(())
 }
 
-async fn shutdown(stream: &mut TcpStream, id: u16, start_time: &Instant) -> Result<()> {
+async fn shutdown(stream: &mut Conn, id: u16, stats: &Stats) -> Result<()> {
     utils::send_ins(stream, id, Operation::RequestSuccess, None).await?;
-    //let time_used = start_time.elapsed().as_secs();
-    //message::send_msg(Message::Status(format!("Time used in transmission: {} seconds", &time_used)));
+    message::send_msg(Message::Status(stats.summary("Received")));
     message::send_msg(Message::Done);
 
     Ok(())
 }
 
-async fn reply_success(stream: &mut TcpStream, id: u16) -> Result<()> {
+async fn reply_success(stream: &mut Conn, id: u16) -> Result<()> {
     utils::send_ins(stream, id, Operation::RequestSuccess, None).await?;
 
     Ok(())
 }
 
-async fn reply_error(stream: &mut TcpStream, id: u16, detail_str: &str) -> Result<()> {
+async fn reply_success_with(stream: &mut Conn, id: u16, detail_str: &str) -> Result<()> {
+    let detail = String::from(detail_str);
+    utils::send_ins(stream, id, Operation::RequestSuccess, Some(&detail)).await?;
+
+    Ok(())
+}
+
+async fn reply_error(stream: &mut Conn, id: u16, detail_str: &str) -> Result<()> {
     let detail = String::from(detail_str);
     utils::send_ins(stream, id, Operation::RequestError, Some(&detail)).await?;
     message::send_msg(Message::Error(detail));
@@ -347,10 +1250,65 @@ async fn reply_error(stream: &mut TcpStream, id: u16, detail_str: &str) -> Resul
     Ok(())
 }
 
-async fn reply_refuse(stream: &mut TcpStream, id: u16, detail_str: &str) -> Result<()> {
+async fn reply_refuse(stream: &mut Conn, id: u16, detail_str: &str) -> Result<()> {
     let detail = String::from(detail_str);
     utils::send_ins(stream, id, Operation::RequestRefuse, Some(&detail)).await?;
     message::send_msg(Message::Status(detail));
 
     Ok(())
 }
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn running_session_test() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let session = Running::new(&running);
+        assert_eq!(running.load(Ordering::SeqCst), 1);
+
+        // A session which panics is not counted any more either.
+        let crashed = std::thread::spawn(move || {
+            let _running = session;
+            panic!("session crashed");
+        });
+        assert!(crashed.join().is_err());
+        assert_eq!(running.load(Ordering::SeqCst), 0);
+    }
+
+    #[async_std::test]
+    async fn oversized_connect_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (s, r) = futures::join!(TcpStream::connect(addr), listener.accept());
+        let (mut s, mut r) = (Conn::new(s.unwrap()), Conn::new(r.unwrap().0));
+
+        // Only the header of a 4GB request comes, the receiver must not wait for or make room for the rest.
+        let ins = Instruction { operation: Operation::Connect, buffer: true, length: u32::MAX, ..Default::default() };
+        s.write_all(&ins.encode()).await.unwrap();
+        let local = Hello::local();
+        let refused = async_std::future::timeout(Duration::from_secs(5), authenticate(&mut r, &local, None));
+        assert!(refused.await.unwrap().is_err());
+    }
+
+    #[async_std::test]
+    async fn discover_wrong_code_test() {
+        let arg = RecvArg { code: String::from("7-crossword-banana"), timeout: Duration::from_secs(5), ..Default::default() };
+        let local = Hello::local();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Only the sender who knows the words gets in, even without a password.
+        for (code, accepted) in [("7-crossword-apple", false), ("7-crossword-banana", true)] {
+            let (s, r) = futures::join!(TcpStream::connect(addr), listener.accept());
+            let mut s = Conn::new(s.unwrap());
+            let (r, peer) = r.unwrap();
+            let password = code::pake_password(code, None);
+            let (conn, _) = futures::join!(handshake(r, peer, &local, &arg),
+                super::super::sender::authenticate(&mut s, Some(&password)));
+            assert_eq!(conn.is_some(), accepted, "{}", code);
+        }
+    }
+}
@@ -0,0 +1,453 @@
+use anyhow::{anyhow, Result};
+use async_std::io;
+use async_std::net::{Shutdown, TcpListener, TcpStream};
+use async_std::prelude::*;
+use futures::channel::{mpsc, oneshot};
+use futures::StreamExt;
+use futures::future::{self, Either};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use super::conn::Conn;
+use super::instruction::Operation;
+use super::message::{self, Message};
+use super::utils;
+
+// Port of the relay if the address doesn't give one.
+pub const DEFAULT_PORT: u16 = 4953;
+
+// Size of the registration: 1 byte role followed by the key to meet on.
+const REGISTER_SIZE: usize = 33;
+
+// How long a peer may wait for the other side: the longest expire a sender can set.
+const WAIT_LIMIT: Duration = Duration::from_secs(u8::MAX as u64 * 60);
+
+// How long a receiver which joined a channel waits for its sender to take it.
+const JOIN_LIMIT: Duration = Duration::from_secs(60);
+
+// Channels one address may hold at the same time, so nobody can take them all.
+const MAX_CHANNELS: usize = 8;
+
+// What the relay keeps between the peers.
+type Shared = Arc<Mutex<Relay>>;
+
+#[derive(Default)]
+struct Relay {
+    waiting: HashMap<[u8; 32], Peer>,   // peers registered and waiting for the other side, by key.
+    channels: HashMap<u8, Channel>,     // channels held by a sender, by number.
+}
+
+// The other side is handed to the task of the waiting peer, which pairs them.
+struct Peer {
+    sender: bool,
+    join: oneshot::Sender<(TcpStream, SocketAddr)>,
+}
+
+// A sender holding a channel gets a token for each receiver that joins,
+// and registers with it on a new connection to meet that receiver.
+struct Channel {
+    addr: SocketAddr,
+    notify: mpsc::UnboundedSender<[u8; 32]>,
+    token: [u8; 32],        // of the last receiver, which may still be waiting.
+}
+
+// Entry function of the relay server.
+pub async fn launch(addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    message::send_msg(Message::Status(format!("Relay listening on {}", listener.local_addr()?)));
+
+    serve(listener).await
+}
+
+// Accept peers on the listener and pair them, forever.
+pub async fn serve(listener: TcpListener) -> Result<()> {
+    let shared: Shared = Arc::new(Mutex::new(Relay::default()));
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let shared = shared.clone();
+        async_std::task::spawn(async move {
+            if let Err(e) = handle_peer(stream, addr, shared).await {
+                log::info!("Relay error with {}: {}", addr, e);
+            }
+        });
+    }
+}
+
+// Ask the relay for a free channel, which is the number of the code.
+// The channel is held as long as the returned connection is open.
+pub async fn open(addr: &str) -> Result<(Conn, u8)> {
+    let mut claim = Conn::new(TcpStream::connect(addr).await?);
+    utils::send_ins(&mut claim, 0, Operation::OpenChannel, None).await?;
+
+    match replied(&mut claim).await?.as_slice() {
+        [number] => Ok((claim, *number)),
+        _ => Err(anyhow!("Invalid channel from relay")),
+    }
+}
+
+// Wait on the held channel for the next receiver, return its token.
+pub async fn next_join(claim: &mut Conn) -> Result<[u8; 32]> {
+    let ins = utils::recv_ins(claim).await?;
+    if ins.operation != Operation::JoinChannel || ins.length != 32 {
+        return Err(anyhow!("Relay closed the channel"));
+    }
+
+    let mut token = [0u8; 32];
+    token.copy_from_slice(&utils::recv_content(claim, 32).await?);
+
+    Ok(token)
+}
+
+// Join the channel of the sender, then wait for it to take this side.
+// The connection returned is a plain pipe to the sender.
+pub async fn join(addr: &str, channel: u8) -> Result<Conn> {
+    let mut stream = Conn::new(TcpStream::connect(addr).await?);
+    utils::send_ins_bytes(&mut stream, 0, Operation::JoinChannel, &[channel]).await?;
+    message::send_msg(Message::Status(format!("Waiting for the other side on relay {}", addr)));
+    replied(&mut stream).await?;
+
+    Ok(stream)
+}
+
+// Connect to the relay and register on the key, then wait for the other side.
+// The key is a token from the relay, or comes from the session secret for the data connections,
+// so nobody else can meet on it.
+// The connection returned is a plain pipe to the other side.
+pub async fn connect(addr: &str, key: &[u8; 32], sender: bool) -> Result<Conn> {
+    let mut stream = Conn::new(TcpStream::connect(addr).await?);
+    let mut content = vec![u8::from(sender)];
+    content.extend_from_slice(key);
+    utils::send_ins_bytes(&mut stream, 0, Operation::Register, &content).await?;
+    replied(&mut stream).await?;
+
+    Ok(stream)
+}
+
+// The channel to join: the number of the code, like `7` in `7-crossword-banana`.
+// The relay gave it to the sender, so it is public and only tells the sessions apart.
+pub fn channel(code: &str) -> Result<u8> {
+    code.split_once('-').and_then(|(number, _)| number.parse().ok())
+        .ok_or_else(|| anyhow!("Code should start with a number"))
+}
+
+// The content of the reply from the relay, or why it refused.
+async fn replied(stream: &mut Conn) -> Result<Vec<u8>> {
+    let ins = utils::recv_ins(stream).await?;
+    let reply = utils::recv_content(stream, ins.length as usize).await?;
+    match ins.operation {
+        Operation::RequestSuccess => Ok(reply),
+        _ => Err(anyhow!("Relay refused: {}", String::from_utf8_lossy(&reply))),
+    }
+}
+
+async fn handle_peer(stream: TcpStream, addr: SocketAddr, shared: Shared) -> Result<()> {
+    let mut conn = Conn::new(stream.clone());
+    let ins = utils::recv_ins(&mut conn).await?;
+    match (ins.operation, ins.length as usize) {
+        (Operation::Register, REGISTER_SIZE) => {
+            let buf = utils::recv_content(&mut conn, REGISTER_SIZE).await?;
+            let mut key = [0u8; 32];
+            key.copy_from_slice(&buf[1..]);
+            register(conn, stream, addr, shared, key, buf[0] == 1).await
+        },
+        (Operation::OpenChannel, 0) => hold_channel(conn, stream, addr, shared).await,
+        (Operation::JoinChannel, 1) => {
+            let channel = utils::recv_content(&mut conn, 1).await?[0];
+            join_channel(conn, stream, addr, shared, channel).await
+        },
+        _ => refuse(&mut conn, "Expecting registration").await,
+    }
+}
+
+async fn refuse(conn: &mut Conn, reason: &str) -> Result<()> {
+    utils::send_ins(conn, 0, Operation::RequestRefuse, Some(&String::from(reason))).await?;
+    Err(anyhow!("{}", reason))
+}
+
+// How a new peer is taken in.
+enum Entered {
+    Paired,     // handed to the waiting peer, whose task pairs them.
+    Waiting(oneshot::Receiver<(TcpStream, SocketAddr)>),
+    Taken,      // a peer of the same role is already waiting on the key.
+}
+
+// Hand the new peer to the waiting one if the other side is there, otherwise wait for the other side.
+// A waiting peer is never replaced while it's there, the new one is refused.
+fn enter(relay: &mut Relay, key: [u8; 32], sender: bool, stream: &TcpStream, addr: SocketAddr) -> Entered {
+    if let Some(p) = relay.waiting.remove(&key) {
+        if p.sender != sender {
+            // If the waiting peer just left, wait in its place.
+            if p.join.send((stream.clone(), addr)).is_ok() {
+                return Entered::Paired;
+            }
+        } else if !p.join.is_canceled() {
+            relay.waiting.insert(key, p);
+            return Entered::Taken;
+        }
+    }
+
+    let (join, joined) = oneshot::channel();
+    relay.waiting.insert(key, Peer { sender, join });
+
+    Entered::Waiting(joined)
+}
+
+async fn register(mut conn: Conn, stream: TcpStream, addr: SocketAddr, shared: Shared, key: [u8; 32],
+    sender: bool) -> Result<()> {
+
+    let entered = enter(&mut shared.lock().unwrap(), key, sender, &stream, addr);
+    match entered {
+        Entered::Paired => Ok(()),
+        Entered::Waiting(joined) => pair(conn, stream, addr, &shared, key, joined, WAIT_LIMIT).await,
+        Entered::Taken => refuse(&mut conn, "Already registered").await,
+    }
+}
+
+// Wait for the other side and splice their streams.
+async fn pair(mut conn: Conn, stream: TcpStream, addr: SocketAddr, shared: &Shared, key: [u8; 32],
+    joined: oneshot::Receiver<(TcpStream, SocketAddr)>, limit: Duration) -> Result<()> {
+
+    log::info!("Relay peer {} waiting", addr);
+    let other = wait_other(&stream, joined, limit).await;
+    if other.is_none() {
+        // Only remove the entry if it is still this peer's, which has stopped waiting.
+        let mut relay = shared.lock().unwrap();
+        if relay.waiting.get(&key).is_some_and(|p| p.join.is_canceled()) {
+            relay.waiting.remove(&key);
+        }
+    }
+
+    let (other, other_addr) = match other {
+        Some(o) => o,
+        None => {
+            log::info!("Relay peer {} gone before the other side came", addr);
+            return Ok(());
+        }
+    };
+
+    message::send_msg(Message::Status(format!("Relay pairs {} with {}", addr, other_addr)));
+    utils::send_ins(&mut conn, 0, Operation::RequestSuccess, None).await?;
+    utils::send_ins(&mut Conn::new(other.clone()), 0, Operation::RequestSuccess, None).await?;
+
+    splice(stream, other).await;
+    log::info!("Relay closes {} and {}", addr, other_addr);
+
+    Ok(())
+}
+
+// Give the sender the lowest free channel and keep it for the sender until it closes the connection.
+// Each receiver which joins the channel is passed on as a token.
+async fn hold_channel(mut conn: Conn, stream: TcpStream, addr: SocketAddr, shared: Shared) -> Result<()> {
+    let (notify, mut tokens) = mpsc::unbounded();
+    let channel = {
+        let mut relay = shared.lock().unwrap();
+        let held = relay.channels.values().filter(|c| c.addr.ip() == addr.ip()).count();
+        let free = (1..100).find(|n| !relay.channels.contains_key(n));
+        match free {
+            Some(n) if held < MAX_CHANNELS => {
+                relay.channels.insert(n, Channel { addr, notify, token: [0u8; 32] });
+                Some(n)
+            },
+            _ => None,
+        }
+    };
+    let channel = match channel {
+        Some(n) => n,
+        None => return refuse(&mut conn, "No free channel").await,
+    };
+
+    log::info!("Relay gives channel {} to {}", channel, addr);
+    let result = async {
+        utils::send_ins_bytes(&mut conn, 0, Operation::RequestSuccess, &[channel]).await?;
+
+        // The sender is silent on this connection, so anything read means it's gone.
+        let mut probe = stream.clone();
+        let mut byte = [0u8; 1];
+        let mut closed = Box::pin(probe.read(&mut byte));
+        let holding = async {
+            while let Either::Left((Some(token), _)) = future::select(tokens.next(), &mut closed).await {
+                utils::send_ins_bytes(&mut conn, 0, Operation::JoinChannel, &token).await?;
+            }
+            Ok(())
+        };
+        io::timeout(WAIT_LIMIT, async { Ok(holding.await) }).await?
+    }.await;
+
+    shared.lock().unwrap().channels.remove(&channel);
+    log::info!("Relay frees channel {}", channel);
+
+    result
+}
+
+// Pass a new token for the receiver to the sender holding the channel, and wait for the sender on it.
+// Only one receiver waits on a channel at a time, the others are refused.
+async fn join_channel(mut conn: Conn, stream: TcpStream, addr: SocketAddr, shared: Shared, channel: u8)
+    -> Result<()> {
+
+    let key: [u8; 32] = rand::random();
+    let entered = {
+        let mut relay = shared.lock().unwrap();
+        let Relay { waiting, channels } = &mut *relay;
+        let notified = match channels.get_mut(&channel) {
+            None => Err("No sender on the channel"),
+            Some(c) if waiting.get(&c.token).is_some_and(|p| !p.join.is_canceled()) => Err("Channel busy"),
+            Some(c) => {
+                c.token = key;
+                c.notify.unbounded_send(key).map_err(|_| "No sender on the channel")
+            },
+        };
+        notified.map(|_| enter(&mut relay, key, false, &stream, addr))
+    };
+
+    match entered {
+        Ok(Entered::Waiting(joined)) => pair(conn, stream, addr, &shared, key, joined, JOIN_LIMIT).await,
+        Ok(_) => Ok(()),
+        Err(reason) => refuse(&mut conn, reason).await,
+    }
+}
+
+// Wait for the other side to be handed over. Give up when the waiting peer closes,
+// sends anything before it is paired, or waits too long.
+// Nothing is read from the stream once the other side comes, as the peer is silent until told.
+async fn wait_other(stream: &TcpStream, joined: oneshot::Receiver<(TcpStream, SocketAddr)>, limit: Duration)
+    -> Option<(TcpStream, SocketAddr)> {
+    let mut probe = stream.clone();
+    let mut byte = [0u8; 1];
+    let closed = Box::pin(probe.read(&mut byte));
+
+    match io::timeout(limit, async { Ok(future::select(joined, closed).await) }).await {
+        Ok(Either::Left((Ok(other), _))) => Some(other),
+        _ => None,
+    }
+}
+
+// Copy bytes both ways until both sides finish.
+// When one side stops writing, the other side sees the end of stream too.
+async fn splice(a: TcpStream, b: TcpStream) {
+    let forward = pipe(a.clone(), b.clone());
+    let backward = pipe(b, a);
+
+    futures::future::join(forward, backward).await;
+}
+
+async fn pipe(mut from: TcpStream, mut to: TcpStream) {
+    if let Err(e) = io::copy(&mut from, &mut to).await {
+        log::debug!("Relay pipe stopped: {}", e);
+    }
+
+    let _ = to.shutdown(Shutdown::Write);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn start() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        async_std::task::spawn(serve(listener));
+
+        addr
+    }
+
+    // Let the relay take in what was sent to it.
+    async fn settle() {
+        async_std::task::sleep(Duration::from_millis(200)).await;
+    }
+
+    #[async_std::test]
+    async fn relay_pair_test() {
+        let addr = start().await;
+        let (mut claim, channel) = open(&addr).await.unwrap();
+        assert_eq!(channel, 1);
+
+        let receiver = async_std::task::spawn({
+            let addr = addr.clone();
+            async move { join(&addr, channel).await.unwrap() }
+        });
+        let token = next_join(&mut claim).await.unwrap();
+        let mut s = connect(&addr, &token, true).await.unwrap();
+        let mut r = receiver.await;
+
+        // The relay only passes the frames, so the encryption is end to end.
+        s.encrypt(b"shared secret", true).unwrap();
+        r.encrypt(b"shared secret", false).unwrap();
+        s.write_all(b"through relay").await.unwrap();
+        assert_eq!(r.read_exact(13).await.unwrap(), b"through relay".to_vec());
+        r.write_all(b"back").await.unwrap();
+        assert_eq!(s.read_exact(4).await.unwrap(), b"back".to_vec());
+
+        // The channel is free again once the sender lets it go.
+        drop(claim);
+        settle().await;
+        assert!(join(&addr, channel).await.is_err());
+    }
+
+    #[async_std::test]
+    async fn relay_shared_number_test() {
+        let addr = start().await;
+
+        // Two sessions never get the same channel.
+        let (mut first, a) = open(&addr).await.unwrap();
+        let (_second, b) = open(&addr).await.unwrap();
+        assert_ne!(a, b);
+
+        // A second receiver on the channel is refused instead of taking the place of the first.
+        let receiver = async_std::task::spawn({
+            let addr = addr.clone();
+            async move { join(&addr, a).await }
+        });
+        let token = next_join(&mut first).await.unwrap();
+        assert!(join(&addr, a).await.is_err());
+        let mut s = connect(&addr, &token, true).await.unwrap();
+        let mut r = receiver.await.unwrap();
+        s.write_all(b"first").await.unwrap();
+        assert_eq!(r.read_exact(5).await.unwrap(), b"first".to_vec());
+
+        // A pairing which fails doesn't end the session, the sender takes the next receiver.
+        drop((s, r));
+        let receiver = async_std::task::spawn({
+            let addr = addr.clone();
+            async move { join(&addr, a).await.unwrap() }
+        });
+        let token = next_join(&mut first).await.unwrap();
+        let mut s = connect(&addr, &token, true).await.unwrap();
+        let mut r = receiver.await;
+        s.write_all(b"again").await.unwrap();
+        assert_eq!(r.read_exact(5).await.unwrap(), b"again".to_vec());
+    }
+
+    #[async_std::test]
+    async fn relay_gone_peer_test() {
+        let addr = start().await;
+        let key = [7u8; 32];
+
+        // A receiver registers and leaves before the sender comes.
+        let gone = connect(&addr, &key, false);
+        assert!(io::timeout(Duration::from_millis(200), async { Ok(gone.await) }).await.is_err());
+        settle().await;
+
+        // The sender waits for the next receiver instead of pairing with the one gone.
+        let sender = async_std::task::spawn({
+            let addr = addr.clone();
+            async move { connect(&addr, &key, true).await.unwrap() }
+        });
+        settle().await;
+
+        // Another sender on the same key is refused, the waiting one stays.
+        assert!(connect(&addr, &key, true).await.is_err());
+        let mut r = connect(&addr, &key, false).await.unwrap();
+        let mut s = sender.await;
+
+        s.write_all(b"paired").await.unwrap();
+        assert_eq!(r.read_exact(6).await.unwrap(), b"paired".to_vec());
+    }
+
+    #[test]
+    fn channel_test() {
+        assert_eq!(channel("7-crossword-banana").unwrap(), 7);
+        assert!(channel("crossword").is_err());
+    }
+}
@@ -1,64 +1,183 @@
 use anyhow::{anyhow, Result};
 use async_std::fs::OpenOptions;
+use async_std::io::SeekFrom;
 use async_std::prelude::*;
-use async_std::net::{UdpSocket, TcpStream};
+use async_std::net::{TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
 use async_std::task::block_on;
-use std::path::PathBuf;
+use if_addrs::Interface;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
 use std::sync::{mpsc, Mutex};
 use std::time::{Duration, Instant};
-use super::arg::SendArg;
-use super::currentfile::CurrentFile;
-use super::instruction::Operation;
+use super::archive::Entry;
+use super::arg::{ConnectMode, SendArg};
+use super::code::{self, Rendezvous};
+use super::conn::Conn;
+use super::hello::{self, Hello, HELLO_SIZE};
+use super::currentfile::{self, CurrentFile};
+use super::instruction::{Instruction, Operation};
+use super::interrupt;
+use super::limit::Limiter;
+use super::manifest::Manifest;
 use super::message::{Message, self};
+use super::meta::{self, Meta};
+use super::net;
+use super::pake;
+use super::relay;
+use super::stats::{Meter, Stats};
+use super::streams::{self, Dispatcher, Link, Range};
 use super::utils;
 
+// Max requests sent without their replies.
+const WINDOW: usize = 32;
+
 // Store refused sockets into a black list.
+// Requests waiting for replies are kept in order with their ids,
+// and files with mismatched checksums wait to be sent again with the attempts so far.
 lazy_static::lazy_static! {
     static ref BLACK_LIST: Mutex<Vec<SocketAddr>> = Mutex::new(Vec::new());
     static ref ID: Mutex<u16> = Mutex::new(1);
+    static ref PENDING: Mutex<VecDeque<(u16, Pending)>> = Mutex::new(VecDeque::new());
+    static ref RESEND: Mutex<Vec<(PathBuf, u8)>> = Mutex::new(Vec::new());
+    static ref DATA: Mutex<Option<Dispatcher>> = Mutex::new(None);
+    static ref LIMIT: Mutex<Limiter> = Mutex::new(Limiter::default());
+    static ref STATS: Mutex<Stats> = Mutex::new(Stats::default());
+}
+
+// Set when the receiver aborts the session, so it's not told again.
+static ABORTED: AtomicBool = AtomicBool::new(false);
+
+// The id of the last file start the receiver refused, so the content of that file stops.
+// 0 for none, as no request has it.
+static REFUSED: AtomicU16 = AtomicU16::new(0);
+
+// What a request in the window is, to handle its reply.
+#[derive(Debug)]
+enum Pending {
+    FileStart(String),      // with the file name.
+    FileEnd { path: PathBuf, attempt: u8, retry: u8, bytes: u64 },    // with the content sent this time.
+    Other,
 }
 
 // Entry function of Sender.
 // Bind on a UDP socket, listen incoming UDP connection,
 // get the target TCP port.
+// Or skip the discovery, wait for the receiver on a known address or connect to it.
 // After expire time the whole process will be terminated.
 pub async fn launch(arg: SendArg) -> Result<()> {    
-    let udp = UdpSocket::bind(("0.0.0.0", 0)).await?;
-    let port = udp.local_addr()?.port();
-    message::send_msg(Message::Status(format!("Connection code: {}", port)));
+    let password = arg.password.clone();
+
+    let (mut stream, link) = match &arg.mode {
+        ConnectMode::Discover => {
+            let ifaces = net::select_interfaces(&arg.interfaces)?;
+            let (udp, code, rendezvous) = bind_code_udp(&ifaces).await?;
+            message::send_msg(Message::Status(format!("Connection code: {}", code)));
+
+            let password = code::pake_password(&code, password.as_ref());
+            let waiting = listen_udp(&udp, &rendezvous, &ifaces, arg.expire, Some(&password));
+            let (stream, socket) = with_timer(arg.expire, waiting).await?;
+            (stream, Link::Connect(socket))
+        },
+        ConnectMode::Listen(addr) => {
+            let listener = TcpListener::bind(addr.as_str()).await?;
+            message::send_msg(Message::Status(format!("Listening on {}", listener.local_addr()?)));
+
+            let stream = with_timer(arg.expire, listen_tcp(&listener, password.as_ref(), arg.timeout)).await?;
+            (stream, Link::Accept(vec![listener]))
+        },
+        ConnectMode::To(addr) => {
+            let (stream, socket) = connect_to(addr, password.as_ref()).await?;
+            (stream, Link::Connect(socket))
+        },
+        ConnectMode::Relay(addr) => {
+            let stream = with_timer(arg.expire, connect_relay(addr, password.as_ref(), arg.timeout)).await?;
+            (stream, Link::Relay(addr.clone()))
+        },
+    };
+
+    // Start sending files and messages.
+    start_sending(&mut stream, arg, &link).await?;
+
+    Ok(())
+}
 
-    // Start timer.
+// Run the timer while waiting for the connection, stop it after getting stream.
+async fn with_timer<T>(expire: u8, waiting: impl Future<Output = Result<T>>) -> Result<T> {
     let (tx, rx) = mpsc::channel();
-    let expire = arg.expire.clone();
     async_std::task::spawn(async move {
         timer(expire, rx).await;
     });
 
-    // Stop timer after getting stream.
-    let password = arg.password.clone();
-    let mut stream = listen_udp(&udp, expire, password.as_ref()).await?;
+    let stream = waiting.await?;
     tx.send(true)?;
-    //message::send_msg(Message::Status(format!("Connection established\n")));
 
-    // Start sending files and messages.
-    start_sending(&mut stream, arg).await?;
+    Ok(stream)
+}
 
-    Ok(())
+// Generate a connection code and bind on the UDP port it maps to,
+// for IPv4 broadcast and IPv6 multicast if available.
+// If the port is taken, try another code.
+async fn bind_code_udp(ifaces: &[Interface]) -> Result<(Vec<UdpSocket>, String, Rendezvous)> {
+    for _ in 0..20 {
+        let code = code::generate();
+        let rendezvous = code::rendezvous(&code)?;
+
+        let udp = match UdpSocket::bind(("0.0.0.0", rendezvous.port)).await {
+            Ok(udp) => udp,
+            Err(e) => {
+                log::debug!("Cannot bind port {} for code {}: {}", rendezvous.port, &code, e);
+                continue;
+            }
+        };
+
+        match net::bind_udp_v6(rendezvous.port, ifaces) {
+            Ok(udp6) => return Ok((vec![udp, udp6], code, rendezvous)),
+            Err(e) if is_addr_in_use(&e) => continue,
+            Err(e) => {
+                log::debug!("IPv6 discovery not available: {}", e);
+                return Ok((vec![udp], code, rendezvous));
+            }
+        }
+    }
+
+    Err(anyhow!("Cannot find a free port for the connection code"))
 }
 
-// Listen UDP socket, until a connection comes with a valid port number,
+fn is_addr_in_use(e: &anyhow::Error) -> bool {
+    match e.downcast_ref::<std::io::Error>() {
+        Some(e) => e.kind() == std::io::ErrorKind::AddrInUse,
+        None => false,
+    }
+}
+
+// Listen UDP sockets, until a connection comes with the nonce of the code and a port number,
 // assume it's the TCP port of the receiver.
-async fn listen_udp(udp: &UdpSocket, expire: u8, password: Option<&String>) -> Result<TcpStream>{
-    let mut buf = [0; 2];
+// Broadcasts from outside the selected interfaces are ignored.
+async fn listen_udp(udp: &[UdpSocket], rendezvous: &Rendezvous, ifaces: &[Interface], expire: u8,
+    password: Option<&String>) -> Result<(Conn, SocketAddr)> {
+
     let start = Instant::now();
 
     loop {
         if start.elapsed().as_secs() > (expire * 60) as u64 { break; }
 
-        let (_, addr) = udp.recv_from(&mut buf).await?;
-        let port = u16::from_be_bytes(buf);
-        let socket = SocketAddr::new(addr.ip(), port);
+        let (buf, addr) = recv_udp(udp).await?;
+        if buf.len() != code::NONCE_SIZE + 2 || buf[..code::NONCE_SIZE] != rendezvous.nonce {
+            log::debug!("Ignore broadcast with invalid nonce from {}", &addr);
+            continue;
+        }
+
+        if !net::in_interfaces(&addr, ifaces) {
+            log::debug!("Ignore broadcast from {} outside the selected interfaces", &addr);
+            continue;
+        }
+
+        // Keep the scope id of link-local IPv6 address by only changing the port.
+        let port = u16::from_be_bytes([buf[code::NONCE_SIZE], buf[code::NONCE_SIZE + 1]]);
+        let mut socket = addr;
+        socket.set_port(port);
 
         // If this socket already in black list, ignore it.
         if BLACK_LIST.lock().unwrap().contains(&socket) {
@@ -69,7 +188,7 @@ async fn listen_udp(udp: &UdpSocket, expire: u8, password: Option<&String>) -> R
         log::debug!("Connection request from {}", socket);
         if let Some(stream) = try_connect_tcp(&socket, password).await? {
             message::send_msg(Message::Status(format!("Connection established with {}\n", &addr)));
-            return Ok(stream);
+            return Ok((stream, socket));
         }
 
         async_std::task::sleep(Duration::from_secs(1)).await;
@@ -78,16 +197,106 @@ async fn listen_udp(udp: &UdpSocket, expire: u8, password: Option<&String>) -> R
     Err(anyhow!("No connection established in time"))
 }
 
+// Receive a datagram from whichever socket gets one first.
+async fn recv_udp(udp: &[UdpSocket]) -> Result<(Vec<u8>, SocketAddr)> {
+    let recvs = udp.iter().map(|socket| Box::pin(async move {
+        let mut buf = [0u8; 64];
+        let (len, addr) = socket.recv_from(&mut buf).await?;
+        Ok::<_, std::io::Error>((buf[..len].to_vec(), addr))
+    }));
+
+    let (result, _, _) = futures::future::select_all(recvs).await;
+
+    Ok(result?)
+}
+
+// Wait for the receiver to connect to the listening address, without discovery.
+// Connections with the wrong password are dropped and the next one is waited for,
+// so are the ones that stop answering in the middle of the handshake.
+async fn listen_tcp(listener: &TcpListener, password: Option<&String>, timeout: Duration) -> Result<Conn> {
+    loop {
+        let (tcp, addr) = listener.accept().await?;
+        let addr = net::canonical(addr);
+        log::debug!("Connection request from {}", addr);
+
+        let stream = match async_std::future::timeout(timeout, try_authenticate(Conn::new(tcp), &addr, password)).await {
+            Ok(stream) => stream?,
+            Err(_) => {
+                message::send_msg(Message::Error(format!("No answer from {} in {} seconds", addr, timeout.as_secs())));
+                None
+            },
+        };
+        if let Some(stream) = stream {
+            message::send_msg(Message::Status(format!("Connection established with {}\n", &addr)));
+            return Ok(stream);
+        }
+    }
+}
+
+// Connect to the receiver on the known address, without discovery.
+// Try each address the host name resolves to.
+async fn connect_to(addr: &str, password: Option<&String>) -> Result<(Conn, SocketAddr)> {
+    for socket in addr.to_socket_addrs().await? {
+        if let Some(stream) = try_connect_tcp(&socket, password).await? {
+            message::send_msg(Message::Status(format!("Connection established with {}\n", &socket)));
+            return Ok((stream, socket));
+        }
+    }
+
+    Err(anyhow!("Cannot connect to {}", addr))
+}
+
+// Meet the receiver on the relay with a code on the channel it gives.
+// The relay only passes the encrypted frames, the words of the code are part of the password.
+// A receiver with the wrong code is dropped and the next one is waited for, like in `listen_tcp()`.
+async fn connect_relay(addr: &str, password: Option<&String>, timeout: Duration) -> Result<Conn> {
+    let (mut claim, channel) = relay::open(addr).await?;
+    let code = code::generate_on(channel);
+    message::send_msg(Message::Status(format!("Connection code: {}", code)));
+    message::send_msg(Message::Status(format!("Waiting for the other side on relay {}", addr)));
+
+    let socket = addr.to_socket_addrs().await?.next().ok_or_else(|| anyhow!("Invalid relay address"))?;
+    let password = code::pake_password(&code, password);
+    loop {
+        let token = relay::next_join(&mut claim).await?;
+        let meeting = async {
+            let stream = relay::connect(addr, &token, true).await?;
+            try_authenticate(stream, &socket, Some(&password)).await
+        };
+        match async_std::future::timeout(timeout, meeting).await {
+            Ok(Ok(Some(stream))) => {
+                message::send_msg(Message::Status(format!("Connection established through relay {}\n", addr)));
+                return Ok(stream);
+            },
+            Ok(Ok(None)) => (),
+            Ok(Err(e)) => log::info!("Cannot meet the receiver on relay {}: {}", addr, e),
+            Err(_) => log::info!("Receiver on relay {} gone in the handshake", addr),
+        }
+    }
+}
+
 // Try to connect to the target machine after receiving its connection request.
 // Only run once for a connection request.
 // Needs reply from receiver to continue next step.
 async fn try_connect_tcp(socket: &SocketAddr, password: Option<&String>) 
-    -> Result<Option<TcpStream>> {
+    -> Result<Option<Conn>> {
     
-    let mut stream = TcpStream::connect(socket).await?;
-    utils::send_ins(&mut stream, 0, Operation::Connect, password).await?;
+    let tcp = match TcpStream::connect(socket).await {
+        Ok(tcp) => tcp,
+        Err(e) => {
+            message::send_msg(Message::Error(format!("Cannot connect to {}: {}", socket, e)));
+            return Ok(None);
+        }
+    };
+
+    try_authenticate(Conn::new(tcp), socket, password).await
+}
 
-    match validate_reply(&mut stream, 0).await {
+// Run the handshake on a new connection, return it only if the receiver accepts.
+async fn try_authenticate(mut stream: Conn, socket: &SocketAddr, password: Option<&String>)
+    -> Result<Option<Conn>> {
+
+    match authenticate(&mut stream, password).await {
         Ok((true, _)) => Ok(Some(stream)),
         Ok((false, detail)) => {
             message::send_msg(Message::Error(format!("Connection refused: {}", detail)));
@@ -101,6 +310,58 @@ async fn try_connect_tcp(socket: &SocketAddr, password: Option<&String>)
     }
 }
 
+// Prove the password with SPAKE2 so it never goes on the wire.
+// 1. Send the hello and PAKE message with Connect, get the receiver's hello, message and confirmation tag.
+//    Agree on the protocol version and capabilities from both hellos.
+// 2. Send the confirmation tag of sender and wait for the receiver to accept it.
+// 3. Check the receiver's tag so a fake receiver cannot pretend to know the password.
+//    The tags cover both hellos, so the capabilities cannot be changed on the way.
+// The rest of the session is encrypted with the PAKE session key.
+pub async fn authenticate(stream: &mut Conn, password: Option<&String>) -> Result<(bool, String)> {
+    let local = Hello::local();
+    let (state, msg) = pake::start(password, true);
+    let mut content = local.encode().to_vec();
+    content.extend_from_slice(&msg);
+    utils::send_ins_bytes(stream, 0, Operation::Connect, &content).await?;
+
+    let (accepted, reply) = validate_reply_bytes(stream, 0).await?;
+    if !accepted {
+        return Ok((false, String::from_utf8(reply)?));
+    }
+
+    if reply.len() <= HELLO_SIZE + pake::CONFIRM_SIZE {
+        return Err(anyhow!("Invalid reply for connection request"));
+    }
+
+    let (remote_hello, rest) = reply.split_at(HELLO_SIZE);
+    let remote = Hello::decode(remote_hello)?;
+    let agreed = match local.negotiate(&remote) {
+        Ok(agreed) => agreed,
+        Err(e) => return Ok((false, e.to_string())),
+    };
+
+    let (remote_msg, remote_tag) = rest.split_at(rest.len() - pake::CONFIRM_SIZE);
+    let key = pake::finish(state, remote_msg)?;
+    let transcript = [local.encode(), remote.encode()].concat();
+    let tag = pake::confirm(&key, true, &transcript);
+    utils::send_ins_bytes(stream, 0, Operation::KeyConfirm, &tag).await?;
+
+    let (accepted, detail) = validate_reply(stream, 0).await?;
+    if !accepted {
+        return Ok((false, detail));
+    }
+
+    if !pake::verify(&key, false, &transcript, remote_tag) {
+        return Err(anyhow!("Receiver cannot prove the password"));
+    }
+
+    stream.encrypt(&key, true)?;
+    stream.caps = agreed.caps;
+    log::debug!("Session encrypted with protocol {:?}", agreed);
+
+    Ok((true, detail))
+}
+
 // Wait for `expire` minutes before terminate the process.
 // Can be interrupted by the signal from parent function.
 async fn timer(expire: u8, rx: mpsc::Receiver<bool>) {
@@ -118,38 +379,138 @@ async fn timer(expire: u8, rx: mpsc::Receiver<bool>) {
         }
     }
 
-    message::send_msg(Message::Fatal(format!("no connection in time")));
+    message::send_msg(Message::Fatal("no connection in time".to_string()));
 }
 
 // After the connection established, start sending files and messages from here.
-async fn start_sending(stream: &mut TcpStream, arg: SendArg) -> Result<()> {
-    if arg.files.is_some() {
-        send_files(stream, &arg.files.unwrap())?;
+// If interrupted or anything goes wrong, the receiver is told why before the session ends.
+async fn start_sending(stream: &mut Conn, arg: SendArg, link: &Link) -> Result<()> {
+    *LIMIT.lock().unwrap() = Limiter::new(arg.limit);
+    *STATS.lock().unwrap() = Stats::new();
+    if let Err(e) = interrupt::watch() {
+        log::info!("Cannot catch Ctrl-C: {}", e);
+    }
+    utils::keep_alive(stream, arg.timeout);
+
+    if let Err(e) = send_session(stream, &arg, link).await {
+        if !ABORTED.load(Ordering::SeqCst) {
+            utils::abort_session(stream, read_id(), &e.to_string()).await;
+        }
+        message::send_msg(Message::Status(STATS.lock().unwrap().summary("Sent")));
+        return Err(e);
+    }
+
+    message::send_msg(Message::Status(STATS.lock().unwrap().summary("Sent")));
+    message::send_msg(Message::Done);
+    Ok(())
+}
+
+async fn send_session(stream: &mut Conn, arg: &SendArg, link: &Link) -> Result<()> {
+    if arg.streams > 0 {
+        open_streams(stream, arg, link).await?;
     }
 
-    if arg.msg.is_some(){
-        send_message(stream, &arg.msg.unwrap()).await?;
+    let files = match &arg.files {
+        Some(files) if stream.caps & hello::CAP_MANIFEST != 0 => Some(send_manifest(stream, files).await?),
+        files => files.clone(),
+    };
+
+    if arg.archive && stream.caps & hello::CAP_ARCHIVE == 0 {
+        message::send_msg(Message::Status("Receiver doesn't support archives, send directories file by file".to_string()));
+    }
+
+    if let Some(files) = &files {
+        send_files(stream, files, arg)?;
+        resend_files(stream, None, arg)?;
+    }
+
+    if let Some(msg) = &arg.msg {
+        send_message(stream, msg).await?;
     }
 
     if request_disconnect(stream).await? {
         log::debug!("Ready to shutdown");
     }
 
-    message::send_msg(Message::Done);
     Ok(())
 }
 
+// Ask the receiver for data connections and open them the same way as the control connection.
+// File content goes over them from then on, the control connection only carries the requests.
+async fn open_streams(stream: &mut Conn, arg: &SendArg, link: &Link) -> Result<()> {
+    if arg.streams > streams::MAX_STREAMS {
+        return Err(anyhow!("At most {} streams are supported", streams::MAX_STREAMS));
+    }
+
+    if stream.caps & hello::CAP_STREAMS == 0 {
+        message::send_msg(Message::Status("Receiver doesn't support multiple streams, send over one connection".to_string()));
+        return Ok(());
+    }
+
+    let id = read_id();
+    utils::send_ins_bytes(stream, id, Operation::OpenStreams, &[arg.streams]).await?;
+    incre_id();
+    if !process_reply(stream, id).await? {
+        return Ok(());
+    }
+
+    let conns = streams::open(link, stream, arg.streams, true, arg.timeout).await?;
+    let compress = arg.compress && stream.caps & hello::CAP_COMPRESSION != 0;
+    *DATA.lock().unwrap() = Some(Dispatcher::new(conns, compress));
+    message::send_msg(Message::Status(format!("Opened {} data connections", arg.streams)));
+
+    Ok(())
+}
+
+// Announce everything first, so the receiver can review it and both sides can show
+// the progress of the whole session. Return the files the receiver accepts.
+// The receiver replies with the indices of the rejected top level items.
+async fn send_manifest(stream: &mut Conn, files: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let manifest = Manifest::collect(files);
+    let id = read_id();
+    utils::send_ins_bytes(stream, id, Operation::SendManifest, &manifest.encode()).await?;
+    incre_id();
+    message::send_msg(Message::Status("Waiting for the receiver to accept the files".to_string()));
+
+    let rejected: Vec<usize> = match validate_reply(stream, id).await? {
+        (true, detail) => detail.split(',').filter(|s| !s.is_empty()).map(|s| s.parse()).collect::<Result<_, _>>()?,
+        (false, detail) => {
+            message::send_msg(Message::Status(detail));
+            return Ok(Vec::new());
+        }
+    };
+    STATS.lock().unwrap().expect(manifest.pick(&rejected));
+
+    // Files which are not listed still go to send_files() to be reported there.
+    let mut index = 0;
+    let accepted = files.iter().filter(|f| {
+        if !Manifest::is_listed(f) {
+            return true;
+        }
+        index += 1;
+        !rejected.contains(&(index - 1))
+    });
+
+    Ok(accepted.cloned().collect())
+}
+
 // identify files and dirs and process them accordingly.
 // Remove `async` of this function to avoid async recursion.
-fn send_files(stream: &mut TcpStream, files: &Vec<PathBuf>) -> Result<()> {
+// An error only skips the file or dir, unless the session is over.
+fn send_files(stream: &mut Conn, files: &Vec<PathBuf>, arg: &SendArg) -> Result<()> {
     for file in files {
+        interrupt::check()?;
         if file.is_file() {
-            if let Err(e) = block_on(send_single_file(stream, file)) {
-                message::send_msg(Message::Error(format!("Error sending file {:?} : {}", file, e)));
+            match block_on(send_single_file(stream, file, arg, 0)) {
+                Err(e) if session_over(stream) => return Err(e),
+                Err(e) => message::send_msg(Message::Error(format!("Error sending file {:?} : {}", file, e))),
+                Ok(()) => (),
             }
         } else if file.is_dir() {
-            if let Err(e) = block_on(send_dir(stream, file)) {
-                message::send_msg(Message::Error(format!("Error sending dir {:?} : {}", file, e)));
+            match block_on(send_dir(stream, file, arg)) {
+                Err(e) if session_over(stream) => return Err(e),
+                Err(e) => message::send_msg(Message::Error(format!("Error sending dir {:?} : {}", file, e))),
+                Ok(()) => (),
             }
         } else {
             return Err(anyhow!("Unknow file type"));
@@ -162,12 +523,18 @@ fn send_files(stream: &mut TcpStream, files: &Vec<PathBuf>) -> Result<()> {
 // 1. Check the directory name first. TODO: if overwrite strategy is overwrite, do not create the dir.
 // 2. Collect all paths inside the current dir and pass them to send_files() function.
 // Async function doesn't support recursion.
-async fn send_dir(stream: &mut TcpStream, dir: &PathBuf) -> Result<()> {
+async fn send_dir(stream: &mut Conn, dir: &Path, arg: &SendArg) -> Result<()> {
+    if arg.archive && stream.caps & hello::CAP_ARCHIVE != 0 {
+        return send_archive(stream, dir, arg).await;
+    }
+
     let id = read_id();
-    let dir_name = dir.file_name().unwrap().to_str().unwrap().to_string();
+    let name = dir.file_name().ok_or_else(|| anyhow!("Cannot read directory name"))?;
+    let dir_name = meta::display_name(name);
+    let record = Meta { name: name.to_os_string(), ..Default::default() };
 
     message::send_msg(Message::Status(format!("Start sending directory: \"{}\"", &dir_name)));
-    utils::send_ins(stream, id, Operation::StartSendDir, Some(&dir_name)).await?;
+    utils::send_ins_bytes(stream, id, Operation::StartSendDir, &record.encode()).await?;
     incre_id();
 
     // If request being refused, abort the following action.
@@ -176,15 +543,19 @@ async fn send_dir(stream: &mut TcpStream, dir: &PathBuf) -> Result<()> {
         return Ok(());
     }
 
-    let mut paths = Vec::new();
-    for entry in dir.read_dir()? {
-        if let Ok(entry) = entry {
-            paths.push(entry.path());
+    // The receiver is in the directory already, it goes back with the abort.
+    let entries = match dir.read_dir() {
+        Ok(entries) => entries,
+        Err(e) => {
+            send_abort(stream, Operation::AbortDir, &format!("Cannot read directory: {}", e)).await?;
+            return Err(e.into());
         }
-    }
+    };
+    let paths: Vec<PathBuf> = entries.flatten().map(|entry| entry.path()).collect();
 
     // Problem unsolved: once the recursion starts, the return type is required to be `dyn Future`.
-    send_files(stream, &paths)?;
+    send_files(stream, &paths, arg)?;
+    resend_files(stream, Some(dir), arg)?;
     
     send_dir_end(stream).await?;
     message::send_msg(Message::Status(format!("Finish sending directory: \"{}\"", &dir_name)));
@@ -192,78 +563,403 @@ async fn send_dir(stream: &mut TcpStream, dir: &PathBuf) -> Result<()> {
     Ok(())
 }
 
-async fn send_dir_end(stream: &mut TcpStream) -> Result<()> {
+async fn send_dir_end(stream: &mut Conn) -> Result<()> {
     let id = read_id();
     utils::send_ins(stream, id, Operation::EndSendDir, None).await?;
     incre_id();
 
-    process_reply(stream, id).await?;
+    track(stream, id, Pending::Other).await
+}
 
-    Ok(())
+// Wait for all replies and send the files with mismatched checksums again,
+// until none is left. Only the files directly in `dir` if given,
+// as the receiver puts them in its current directory.
+fn resend_files(stream: &mut Conn, dir: Option<&Path>, arg: &SendArg) -> Result<()> {
+    loop {
+        block_on(drain(stream))?;
+
+        let files: Vec<(PathBuf, u8)> = {
+            let mut resend = RESEND.lock().unwrap();
+            let (files, rest) = resend.drain(..)
+                .partition(|(path, _)| dir.is_none() || path.parent() == dir);
+            *resend = rest;
+            files
+        };
+
+        if files.is_empty() {
+            return Ok(());
+        }
+
+        for (file, attempt) in files {
+            interrupt::check()?;
+            match block_on(send_single_file(stream, &file, arg, attempt)) {
+                Err(e) if session_over(stream) => return Err(e),
+                Err(e) => message::send_msg(Message::Error(format!("Error sending file {:?} : {}", file, e))),
+                Ok(()) => (),
+            }
+        }
+    }
 }
 
-// If any error happens or receiver chooses skip, skip this file.
-async fn send_single_file(stream: &mut TcpStream, file: &PathBuf) -> Result<()> {
-    let mut current_file = CurrentFile::from(file)?;
-    if !send_file_meta(stream, &current_file).await? {
+// Stream the whole tree as one archive, without waiting for a reply for each file.
+// The walk keeps a stack of the directories entered, so there's no recursion.
+async fn send_archive(stream: &mut Conn, dir: &Path, arg: &SendArg) -> Result<()> {
+    let id = read_id();
+    let name = dir.file_name().ok_or_else(|| anyhow!("Cannot read directory name"))?;
+    let dir_name = meta::display_name(name);
+    let record = Meta { name: name.to_os_string(), ..Default::default() };
+
+    message::send_msg(Message::Status(format!("Start sending directory as archive: \"{}\"", &dir_name)));
+    utils::send_ins_bytes(stream, id, Operation::StartArchive, &record.encode()).await?;
+    incre_id();
+
+    if !process_reply(stream, id).await? {
         return Ok(());
     }
 
-    send_file_content(stream, &mut current_file).await?;
+    let compress = arg.compress && stream.caps & hello::CAP_COMPRESSION != 0;
+    let mut writer = ArchiveWriter {
+        compress,
+        limiter: LIMIT.lock().unwrap().clone(),
+        stats: STATS.lock().unwrap().clone(),
+        ..Default::default()
+    };
+    let mut stack = match dir.read_dir() {
+        Ok(entries) => vec![entries],
+        Err(e) => {
+            send_abort(stream, Operation::AbortDir, &format!("Cannot read directory: {}", e)).await?;
+            return Err(e.into());
+        }
+    };
+
+    while let Some(entries) = stack.last_mut() {
+        let entry = match entries.next() {
+            Some(Ok(entry)) => entry,
+            Some(Err(e)) => {
+                message::send_msg(Message::Error(format!("Cannot read directory entry: {}", e)));
+                continue;
+            },
+            None => {
+                stack.pop();
+                if !stack.is_empty() {
+                    writer.write(stream, &Entry::Up.encode()).await?;
+                }
+                continue;
+            }
+        };
+
+        let path = entry.path();
+        if path.is_dir() {
+            let entries = match path.read_dir() {
+                Ok(entries) => entries,
+                Err(e) => {
+                    message::send_msg(Message::Error(format!("Cannot read dir {:?}: {}", path, e)));
+                    continue;
+                }
+            };
+
+            let record = Meta { name: entry.file_name(), ..Default::default() };
+            writer.write(stream, &Entry::Dir(record).encode()).await?;
+            stack.push(entries);
+        } else if path.is_file() {
+            // Nothing is written for a file which cannot be opened, so it's just left out.
+            let file = match CurrentFile::from(&path) {
+                Ok(file) => file,
+                Err(e) => {
+                    message::send_msg(Message::Error(format!("Cannot read file {:?}: {}", path, e)));
+                    continue;
+                }
+            };
+            let fd = match OpenOptions::new().read(true).open(&path).await {
+                Ok(fd) => fd,
+                Err(e) => {
+                    message::send_msg(Message::Error(format!("Cannot read file {:?}: {}", path, e)));
+                    continue;
+                }
+            };
+
+            archive_file(stream, &mut writer, &file, fd).await?;
+        }
+    }
+
+    writer.flush(stream).await?;
+    message::send_msg(Message::FileEnd);
+
+    let id = read_id();
+    let digest = writer.hasher.finalize().to_hex().to_string();
+    utils::send_ins(stream, id, Operation::EndArchive, Some(&digest)).await?;
+    incre_id();
+
+    // The receiver may leave out entries with unsafe names and tell which in the detail.
+    match validate_reply(stream, id).await? {
+        (true, detail) => {
+            if !detail.is_empty() {
+                message::send_msg(Message::Error(detail));
+            }
+            message::send_msg(Message::Status(format!("Finish sending directory: \"{}\", {} files, {}",
+                &dir_name, writer.files, currentfile::human_read_size(writer.bytes))));
+        },
+        (false, detail) => message::send_msg(Message::Status(detail)),
+    }
+
+    Ok(())
+}
+
+// Write the file entry and its content to the archive.
+// The size is already in the entry, so if the file changes while being read,
+// the content is cut or padded with zeros to keep the archive readable.
+async fn archive_file(stream: &mut Conn, writer: &mut ArchiveWriter, file: &CurrentFile,
+    fd: async_std::fs::File) -> Result<()> {
+
+    writer.write(stream, &Entry::File(file.to_meta()).encode()).await?;
 
-    send_file_end(stream).await?;
+    let mut reader = fd.take(file.size);
+    let mut buf = vec![0u8; 0x10000];
+    let mut left = file.size;
+
+    while left > 0 {
+        let n = match reader.read(&mut buf).await {
+            Ok(n) if n > 0 => n,
+            result => {
+                if let Err(e) = result {
+                    log::debug!("Read error: {}", e);
+                }
+                message::send_msg(Message::Error(format!("File {:?} changed while sending, the copy is incomplete",
+                    meta::display_name(&file.name))));
+                let padding = vec![0u8; 0x10000];
+                while left > 0 {
+                    let n = left.min(padding.len() as u64) as usize;
+                    writer.write_content(stream, &padding[..n]).await?;
+                    left -= n as u64;
+                }
+                break;
+            }
+        };
+
+        writer.write_content(stream, &buf[..n]).await?;
+        left -= n as u64;
+    }
+
+    writer.files += 1;
+    writer.stats.file_done();
 
     Ok(())
 }
 
+// Buffer of the archive stream, sent in chunks of the max size.
+#[derive(Default)]
+struct ArchiveWriter {
+    buf: Vec<u8>,
+    hasher: blake3::Hasher,     // checksum of the whole archive stream.
+    compress: bool,
+    limiter: Limiter,
+    meter: Meter,
+    stats: Stats,
+    files: u64,
+    bytes: u64,     // size of the file content archived.
+}
+
+impl ArchiveWriter {
+    async fn write(&mut self, stream: &mut Conn, data: &[u8]) -> Result<()> {
+        self.buf.extend_from_slice(data);
+        let chunk_size = self.limiter.chunk_size(utils::MAX_CHUNK);
+        while self.buf.len() >= chunk_size {
+            let rest = self.buf.split_off(chunk_size);
+            let chunk = std::mem::replace(&mut self.buf, rest);
+            self.send(stream, &chunk).await?;
+        }
+
+        Ok(())
+    }
+
+    // File content counts in the progress, the entries don't.
+    async fn write_content(&mut self, stream: &mut Conn, data: &[u8]) -> Result<()> {
+        self.bytes += data.len() as u64;
+        self.stats.add_bytes(data.len() as u64);
+        self.write(stream, data).await
+    }
+
+    async fn flush(&mut self, stream: &mut Conn) -> Result<()> {
+        if !self.buf.is_empty() {
+            let chunk = std::mem::take(&mut self.buf);
+            self.send(stream, &chunk).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn send(&mut self, stream: &mut Conn, chunk: &[u8]) -> Result<()> {
+        interrupt::check()?;
+        poll_replies(stream).await?;
+        self.limiter.take(chunk.len()).await;
+        utils::send_chunk(stream, read_id(), Operation::ArchiveData, chunk, self.compress).await?;
+        self.hasher.update(chunk);
+        self.meter.update(self.bytes);
+        message::send_msg(Message::Progress(format!("Archive: {} files\t\tProgress: {}, {}\t\t{}",
+            self.files, currentfile::human_read_size(self.bytes), self.meter.describe(None), self.stats.progress())));
+
+        Ok(())
+    }
+}
+
+// If any error happens or receiver chooses skip, skip this file.
+// If the receiver reports a checksum mismatch, the file is sent again up to `arg.retry` times,
+// see `handle_reply()`. `attempt` is the times it has been sent again.
+async fn send_single_file(stream: &mut Conn, file: &Path, arg: &SendArg, attempt: u8) -> Result<()> {
+    let mut current_file = CurrentFile::from(file)?;
+    let file_id = read_id();
+
+    // Files in one chunk are not worth waiting for the receiver to get ready for ranges.
+    let data = match current_file.size > utils::MAX_CHUNK as u64 {
+        true => DATA.lock().unwrap().clone(),
+        false => None,
+    };
+    let offset = match send_file_meta(stream, &current_file, data.is_some()).await? {
+        Some(offset) => offset,
+        None => {
+            STATS.lock().unwrap().skip(1, current_file.size);
+            return Ok(());
+        },
+    };
+
+    let compress = arg.compress && stream.caps & hello::CAP_COMPRESSION != 0;
+    if !send_file_content(stream, &mut current_file, file_id, offset, compress, data).await? {
+        return Ok(());
+    }
+
+    let bytes = current_file.transmitted - current_file.offset;
+    let end = Pending::FileEnd { path: file.to_path_buf(), attempt, retry: arg.retry, bytes };
+    send_file_end(stream, &current_file.digest(), end).await
+}
+
 // Send file name and size as metainfo to receiver.
-async fn send_file_meta(stream: &mut TcpStream, file: &CurrentFile) -> Result<bool> {
-    let meta = file.meta_to_string();
+// Return the offset to start sending from, or None if the file is refused.
+// The receiver replies with an offset only when it resumes a partial file,
+// otherwise the content is sent without waiting for the reply,
+// unless it goes over the data connections as `ranged`, which need the file to be ready.
+async fn send_file_meta(stream: &mut Conn, file: &CurrentFile, ranged: bool) -> Result<Option<u64>> {
+    let meta = file.to_meta().encode();
     let id = read_id();
-    utils::send_ins(stream, id, Operation::StartSendFile, Some(&meta)).await?;
+    REFUSED.store(0, Ordering::SeqCst);
+    utils::send_ins_bytes(stream, id, Operation::StartSendFile, &meta).await?;
     incre_id();
 
-    process_reply(stream, id).await
+    if stream.caps & hello::CAP_RESUME == 0 && !ranged {
+        track(stream, id, Pending::FileStart(meta::display_name(&file.name))).await?;
+        return Ok(Some(0));
+    }
+
+    match validate_reply(stream, id).await? {
+        (true, detail) if detail.is_empty() => Ok(Some(0)),
+        (true, detail) => {
+            let offset: u64 = detail.parse()?;
+            if offset > file.size {
+                return Err(anyhow!("Invalid resume offset {}", offset));
+            }
+
+            Ok(Some(offset))
+        },
+        (false, detail) => {
+            message::send_msg(Message::Status(detail));
+            Ok(None)
+        }
+    }
 }
 
-async fn send_file_content(stream: &mut TcpStream, f: &mut CurrentFile) -> Result<()> {
-    log::debug!("Sending file content");
+// With data connections, the chunks go over them as ranges of the file,
+// with the id of its start request.
+// Return false if the receiver refused the file meanwhile, then the rest isn't sent.
+async fn send_file_content(stream: &mut Conn, f: &mut CurrentFile, id: u16, offset: u64, compress: bool,
+    data: Option<Dispatcher>) -> Result<bool> {
+    log::debug!("Sending file content from offset {}", offset);
+    let mut data = data;
+    let limiter = LIMIT.lock().unwrap().clone();
+    let stats = STATS.lock().unwrap().clone();
     let mut file = OpenOptions::new().read(true).open(f.path.clone()).await?;
-    let chunk_size = 0x200000;  // 2M frame size
+    let chunk_size = limiter.chunk_size(utils::MAX_CHUNK);  // 2M frame size without limit
+
+    // The skipped content still counts in the checksum.
+    if offset > 0 {
+        f.hash_existing(offset).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        f.transmitted = offset;
+        f.offset = offset;
+        stats.skip(0, offset);
+    }
+    f.start();
 
     loop {
+        interrupt::check()?;
+        poll_replies(stream).await?;
+        if REFUSED.load(Ordering::SeqCst) == id {
+            stats.discard(1, f.transmitted - f.offset);
+            stats.skip(0, f.size - f.transmitted);
+            message::send_msg(Message::FileEnd);
+            incre_id();
+            return Ok(false);
+        }
+
+        // The receiver gives up the file with the abort, the session goes on.
         let mut chunk = Vec::with_capacity(chunk_size);
-        let length = file.by_ref().take(chunk_size as u64).read_to_end(&mut chunk).await?;
+        let length = match file.by_ref().take(chunk_size as u64).read_to_end(&mut chunk).await {
+            Ok(length) => length,
+            Err(e) => {
+                stats.skip(1, f.size - f.transmitted);
+                message::send_msg(Message::FileEnd);
+                send_abort(stream, Operation::AbortFile, &format!("Cannot read file: {}", e)).await?;
+                return Err(e.into());
+            }
+        };
         if length == 0 { break; }
 
-        utils::send_ins_bytes(stream, read_id(), Operation::SendFileContent, &chunk).await?;
-        f.transmitted += length as u64;
-        message::send_msg(Message::Progress(f.get_progress()));
+        limiter.take(length).await;
+        f.hasher.update(&chunk);
+        match data.as_mut() {
+            Some(d) => stream.alive(d.send(Range { id, offset: f.transmitted, chunk })).await??,
+            None => utils::send_chunk(stream, read_id(), Operation::SendFileContent, &chunk, compress).await?,
+        }
+        f.advance(length as u64);
+        stats.add_bytes(length as u64);
+        message::send_msg(Message::Progress(format!("{}\t\t{}", f.get_progress(), stats.progress())));
     }
 
     message::send_msg(Message::FileEnd);
     incre_id();
 
-    Ok(())
+    Ok(true)
 }
 
-async fn send_file_end(stream: &mut TcpStream) -> Result<bool> {
+// Send the checksum of the file content along with the end request.
+async fn send_file_end(stream: &mut Conn, digest: &String, end: Pending) -> Result<()> {
     let id = read_id();
-    utils::send_ins(stream, id, Operation::EndSendFile, None).await?;
+    utils::send_ins(stream, id, Operation::EndSendFile, Some(digest)).await?;
 
     incre_id();
-    process_reply(stream, id).await
+    track(stream, id, end).await
 }
 
-async fn send_message(stream: &mut TcpStream, msg: &String) -> Result<bool> {
+// Tell the receiver to give up the current file or directory, if it knows the abort.
+async fn send_abort(stream: &mut Conn, operation: Operation, reason: &str) -> Result<()> {
+    if stream.caps & hello::CAP_ABORT == 0 {
+        return Ok(());
+    }
+
+    let id = read_id();
+    utils::send_ins(stream, id, operation, Some(&reason.to_string())).await?;
+    incre_id();
+
+    Ok(())
+}
+
+async fn send_message(stream: &mut Conn, msg: &String) -> Result<()> {
     let id = read_id();
     utils::send_ins(stream, id, Operation::SendMsg, Some(msg)).await?;
 
     incre_id();
-    process_reply(stream, id).await
+    track(stream, id, Pending::Other).await
 }
 
-async fn request_disconnect(stream: &mut TcpStream) -> Result<bool> {
+async fn request_disconnect(stream: &mut Conn) -> Result<bool> {
     let id = read_id();
     utils::send_ins(stream, id, Operation::Disconnect, None).await?;
 
@@ -276,7 +972,7 @@ async fn request_disconnect(stream: &mut TcpStream) -> Result<bool> {
     }
 }
 
-async fn process_reply(stream: &mut TcpStream, id: u16) -> Result<bool> {
+async fn process_reply(stream: &mut Conn, id: u16) -> Result<bool> {
     match validate_reply(stream, id).await? {
         (true, _) => Ok(true),
         (false, detail) => {
@@ -288,31 +984,142 @@ async fn process_reply(stream: &mut TcpStream, id: u16) -> Result<bool> {
 
 // Validate the reply id and the reply operation.
 // For abnormal reply, read the details as well.
-async fn validate_reply(stream: &mut TcpStream, id: u16) -> Result<(bool, String)> {
-    let reply = utils::recv_ins(stream).await?;
-    if reply.id != id {
-        return Err(anyhow!("wrong id in reply"));
-    }
+async fn validate_reply(stream: &mut Conn, id: u16) -> Result<(bool, String)> {
+    let (success, detail) = validate_reply_bytes(stream, id).await?;
 
-    let detail = if reply.buffer {
-        get_reply_content(stream, reply.length as usize).await?
-    } else {
-        String::new()
+    Ok((success, String::from_utf8(detail)?))
+}
+
+// Same as validate_reply() but keep the details of a successful reply in bytes.
+// Replies to the requests sent before come first and are handled on the way.
+async fn validate_reply_bytes(stream: &mut Conn, id: u16) -> Result<(bool, Vec<u8>)> {
+    let (reply, detail) = loop {
+        let (reply, detail) = recv_reply(stream).await?;
+        if reply.id == id {
+            break (reply, detail);
+        }
+
+        handle_reply(&reply, &detail)?;
     };
 
     match reply.operation {
         Operation::RequestSuccess => Ok((true, detail)),
         Operation::RequestRefuse => Ok((false, detail)),
-        Operation::RequestError => Err(anyhow!(detail)),
+        Operation::RequestError => Err(anyhow!(String::from_utf8_lossy(&detail).to_string())),
         _ => Err(anyhow!("Unknown reply")),
     }
 }
 
-// Helper function to read details for validate_reply().
-async fn get_reply_content(stream: &mut TcpStream, length: usize) -> Result<String> {
-    let detail = utils::recv_content(stream, length).await?;
+async fn recv_reply(stream: &mut Conn) -> Result<(Instruction, Vec<u8>)> {
+    let reply = interrupt::or_interrupt(utils::recv_ins(stream)).await?;
+    recv_detail(stream, reply).await
+}
+
+// The receiver may abort the session instead of a reply.
+async fn recv_detail(stream: &mut Conn, reply: Instruction) -> Result<(Instruction, Vec<u8>)> {
+    if reply.operation == Operation::AbortSession {
+        ABORTED.store(true, Ordering::SeqCst);
+        return Err(anyhow!("Receiver aborted the session: {}", utils::recv_reason(stream, &reply).await?));
+    }
+
+    let detail = if reply.buffer {
+        utils::recv_content(stream, reply.length as usize).await?
+    } else {
+        Vec::new()
+    };
+
+    Ok((reply, detail))
+}
+
+// Handle the replies which already came, without waiting for more.
+// An abort from the receiver comes this way while a file is being sent.
+async fn poll_replies(stream: &mut Conn) -> Result<()> {
+    while let Some(reply) = utils::poll_ins(stream).await? {
+        let (reply, detail) = recv_detail(stream, reply).await?;
+        handle_reply(&reply, &detail)?;
+    }
 
-    Ok(String::from_utf8(detail)?)
+    Ok(())
+}
+
+// Keep the request in the window instead of waiting for its reply.
+// When the window is full, handle the oldest replies first.
+async fn track(stream: &mut Conn, id: u16, pending: Pending) -> Result<()> {
+    PENDING.lock().unwrap().push_back((id, pending));
+
+    loop {
+        let full = PENDING.lock().unwrap().len() > WINDOW;
+        if !full {
+            return Ok(());
+        }
+
+        let (reply, detail) = recv_reply(stream).await?;
+        handle_reply(&reply, &detail)?;
+    }
+}
+
+// Handle the replies of all requests in the window.
+async fn drain(stream: &mut Conn) -> Result<()> {
+    loop {
+        let empty = PENDING.lock().unwrap().is_empty();
+        if empty {
+            return Ok(());
+        }
+
+        let (reply, detail) = recv_reply(stream).await?;
+        handle_reply(&reply, &detail)?;
+    }
+}
+
+// Match the reply to its request in the window by id.
+// Refusals and errors only affect the request they belong to,
+// a file with a mismatched checksum is put aside to be sent again.
+fn handle_reply(reply: &Instruction, detail: &[u8]) -> Result<()> {
+    let pending = {
+        let mut pending = PENDING.lock().unwrap();
+        match pending.iter().position(|(id, _)| *id == reply.id) {
+            Some(i) => pending.remove(i).map(|(_, p)| p),
+            None => None,
+        }
+    };
+
+    let pending = pending.ok_or_else(|| anyhow!("wrong id in reply"))?;
+    let detail = String::from_utf8_lossy(detail).to_string();
+    log::debug!("Reply {:?} for {:?}", reply.operation, pending);
+
+    match (reply.operation, pending) {
+        (Operation::RequestSuccess, Pending::FileEnd { .. }) => STATS.lock().unwrap().file_done(),
+        (Operation::RequestSuccess, _) => (),
+        (Operation::RequestRefuse, Pending::FileStart(name)) => {
+            REFUSED.store(reply.id, Ordering::SeqCst);
+            message::send_msg(Message::Status(format!("{}: \"{}\"", detail, name)));
+        },
+        (Operation::RequestError, Pending::FileStart(name)) => {
+            REFUSED.store(reply.id, Ordering::SeqCst);
+            message::send_msg(Message::Error(format!("Error sending file \"{}\" : {}", name, detail)));
+        },
+        // The refusal of the file was shown with the reply to its start.
+        // Its content was counted as sent, but the receiver threw it away.
+        (Operation::RequestRefuse, Pending::FileEnd { bytes, .. }) => STATS.lock().unwrap().discard(1, bytes),
+        (Operation::RequestError, Pending::FileEnd { path, attempt, retry, .. }) if attempt < retry => {
+            message::send_msg(Message::Error(format!("{}, sending again ({}/{})", detail, attempt + 1, retry)));
+            RESEND.lock().unwrap().push((path, attempt + 1));
+        },
+        (Operation::RequestError, Pending::FileEnd { bytes, .. }) => {
+            message::send_msg(Message::Error(detail));
+            STATS.lock().unwrap().discard(1, bytes);
+        },
+        (Operation::RequestRefuse, _) => message::send_msg(Message::Status(detail)),
+        (Operation::RequestError, _) => message::send_msg(Message::Error(detail)),
+        _ => return Err(anyhow!("Unknown reply")),
+    }
+
+    Ok(())
+}
+
+// Whether the session cannot go on with the next file.
+fn session_over(stream: &Conn) -> bool {
+    interrupt::is_set() || ABORTED.load(Ordering::SeqCst) || stream.is_lost()
 }
 
 // Increment ID by 1. If it reaches the boundary of U16, set it to 1.
@@ -326,4 +1133,73 @@ fn read_id() -> u16 {
     let id = ID.lock().unwrap();
 
     *id
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn pair() -> (Conn, Conn) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (s, r) = futures::join!(TcpStream::connect(addr), listener.accept());
+
+        (Conn::new(s.unwrap()), Conn::new(r.unwrap().0))
+    }
+
+    #[async_std::test]
+    async fn refused_file_stops_test() {
+        let path = std::env::temp_dir().join(format!("isend_refused_{}", std::process::id()));
+        std::fs::write(&path, vec![7u8; 16 * utils::MAX_CHUNK]).unwrap();
+        let (mut s, mut r) = pair().await;
+
+        // The receiver refuses the file as soon as it gets the start, then reads what comes after.
+        let receiving = async_std::task::spawn(async move {
+            let start = utils::recv_ins(&mut r).await.unwrap();
+            utils::recv_content(&mut r, start.length as usize).await.unwrap();
+            let reason = String::from("File refused: user chose skip");
+            utils::send_ins(&mut r, start.id, Operation::RequestRefuse, Some(&reason)).await.unwrap();
+
+            let mut ops = Vec::new();
+            while let Ok(ins) = utils::recv_ins(&mut r).await {
+                utils::recv_content(&mut r, ins.length as usize).await.unwrap();
+                ops.push(ins.operation);
+            }
+            ops
+        });
+
+        send_single_file(&mut s, &path, &SendArg::default(), 0).await.unwrap();
+        drop(s);
+        let ops = receiving.await;
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(ops.len() < 16, "{} chunks sent", ops.len());
+        assert!(ops.iter().all(|op| *op == Operation::SendFileContent));
+        assert!(PENDING.lock().unwrap().is_empty());
+
+        // A small file is sent whole before the refusals of its start and end come back.
+        std::fs::write(&path, b"small file").unwrap();
+        let (mut s, mut r) = pair().await;
+        let receiving = async_std::task::spawn(async move {
+            let mut ids = Vec::new();
+            for _ in 0..3 {
+                let ins = utils::recv_ins(&mut r).await.unwrap();
+                utils::recv_content(&mut r, ins.length as usize).await.unwrap();
+                ids.push(ins.id);
+            }
+            let reason = String::from("File refused: user chose skip");
+            utils::send_ins(&mut r, ids[0], Operation::RequestRefuse, Some(&reason)).await.unwrap();
+            utils::send_ins(&mut r, ids[2], Operation::RequestRefuse, Some(&reason)).await.unwrap();
+        });
+
+        send_single_file(&mut s, &path, &SendArg::default(), 0).await.unwrap();
+        receiving.await;
+        drain(&mut s).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Nothing of either file counts as sent.
+        let summary = STATS.lock().unwrap().summary("Sent");
+        assert!(summary.starts_with("Sent 0 files, 0B in"), "{}", summary);
+        assert!(summary.ends_with("2 files skipped or failed"), "{}", summary);
+    }
+}
@@ -0,0 +1,219 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use super::currentfile::human_read_size;
+use super::manifest::Manifest;
+
+// How often the current rate is sampled.
+const SAMPLE: Duration = Duration::from_millis(500);
+
+// Throughput of a transfer: the average since the start,
+// and the current rate smoothed over the recent samples.
+#[derive(Clone, Debug)]
+pub struct Meter {
+    started: Instant,
+    base: u64,          // bytes before the start, like the resumed part of a file.
+    bytes: u64,         // bytes so far, including the base.
+    last: Instant,
+    last_bytes: u64,
+    current: Option<f64>,
+}
+
+impl Default for Meter {
+    fn default() -> Self {
+        Meter::new(0)
+    }
+}
+
+impl Meter {
+    pub fn new(base: u64) -> Self {
+        let now = Instant::now();
+        Meter { started: now, base, bytes: base, last: now, last_bytes: base, current: None }
+    }
+
+    // `bytes` is the total so far.
+    pub fn update(&mut self, bytes: u64) {
+        self.bytes = bytes;
+        let elapsed = self.last.elapsed();
+        if elapsed < SAMPLE {
+            return;
+        }
+
+        let rate = bytes.saturating_sub(self.last_bytes) as f64 / elapsed.as_secs_f64();
+        self.current = Some(match self.current {
+            Some(c) => c * 0.6 + rate * 0.4,
+            None => rate,
+        });
+        self.last = Instant::now();
+        self.last_bytes = bytes;
+    }
+
+    pub fn average(&self) -> f64 {
+        let secs = self.started.elapsed().as_secs_f64().max(0.001);
+
+        (self.bytes - self.base) as f64 / secs
+    }
+
+    // The current rate until there's a sample, then the average.
+    fn rate(&self) -> f64 {
+        self.current.unwrap_or_else(|| self.average())
+    }
+
+    pub fn eta(&self, remaining: u64) -> Option<Duration> {
+        match self.rate() {
+            r if r >= 1.0 => Some(Duration::from_secs_f64(remaining as f64 / r)),
+            _ => None,
+        }
+    }
+
+    // Like `2.1MB/s (avg 1.9MB/s), ETA 3s`, without the ETA if the total is unknown.
+    pub fn describe(&self, total: Option<u64>) -> String {
+        let mut s = format!("{} (avg {})", human_read_rate(self.rate()), human_read_rate(self.average()));
+        if let Some(eta) = total.and_then(|t| self.eta(t.saturating_sub(self.bytes))) {
+            s.push_str(&format!(", ETA {}", human_read_time(eta)));
+        }
+
+        s
+    }
+}
+
+// Totals of the whole session, shared by the files and data connections.
+// The totals to expect come from the manifest, if the sender sent one.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    session: Arc<Mutex<Session>>,
+}
+
+#[derive(Debug, Default)]
+struct Session {
+    manifest: Option<Manifest>,
+    files: u64,
+    bytes: u64,             // file content transmitted.
+    skipped_files: u64,     // refused or failed, they are not coming any more.
+    skipped_bytes: u64,     // also the resumed parts which are not transmitted.
+    meter: Meter,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats::default()
+    }
+
+    pub fn expect(&self, manifest: Manifest) {
+        self.session.lock().unwrap().manifest = Some(manifest);
+    }
+
+    pub fn add_bytes(&self, n: u64) {
+        let mut session = self.session.lock().unwrap();
+        session.bytes += n;
+        let done = session.bytes + session.skipped_bytes;
+        session.meter.update(done);
+    }
+
+    pub fn file_done(&self) {
+        self.session.lock().unwrap().files += 1;
+    }
+
+    // Files counted as done but given up after all, like the files of an archive which doesn't finish.
+    pub fn undo(&self, files: u64) {
+        let mut session = self.session.lock().unwrap();
+        session.files -= files;
+        session.skipped_files += files;
+    }
+
+    // Bytes counted as transmitted but thrown away by the other side, like the content of a file
+    // it refused after it was sent. They are skipped instead, so the rate stays the same.
+    pub fn discard(&self, files: u64, bytes: u64) {
+        let mut session = self.session.lock().unwrap();
+        let bytes = bytes.min(session.bytes);
+        session.bytes -= bytes;
+        session.skipped_files += files;
+        session.skipped_bytes += bytes;
+    }
+
+    pub fn skip(&self, files: u64, bytes: u64) {
+        let mut session = self.session.lock().unwrap();
+        session.skipped_files += files;
+        session.skipped_bytes += bytes;
+        // The skipped bytes are not transmitted, so they don't count in the rate.
+        session.meter.base += bytes;
+        session.meter.bytes += bytes;
+        session.meter.last_bytes += bytes;
+    }
+
+    // Like `Total: 3/10 files, 5.0MB/20.0MB, ETA 8s`.
+    pub fn progress(&self) -> String {
+        let session = self.session.lock().unwrap();
+        let done = session.files + session.skipped_files;
+        let bytes = session.bytes + session.skipped_bytes;
+
+        match &session.manifest {
+            Some(m) => {
+                let eta = match session.meter.eta(m.bytes.saturating_sub(bytes)) {
+                    Some(t) => format!(", ETA {}", human_read_time(t)),
+                    None => String::new(),
+                };
+                format!("Total: {}/{} files, {}/{}{}", done, m.files, human_read_size(bytes), human_read_size(m.bytes), eta)
+            },
+            None => format!("Total: {} files, {}", done, human_read_size(bytes)),
+        }
+    }
+
+    // Printed at the end of the session, `verb` is sent or received.
+    // If the session ends early, what the manifest has left is not transferred.
+    pub fn summary(&self, verb: &str) -> String {
+        let session = self.session.lock().unwrap();
+        let mut s = format!("{} {} files, {} in {}, average {}", verb, session.files, human_read_size(session.bytes),
+            human_read_time(session.meter.started.elapsed()), human_read_rate(session.meter.average()));
+        if session.skipped_files > 0 {
+            s.push_str(&format!(", {} files skipped or failed", session.skipped_files));
+        }
+        if let Some(m) = &session.manifest {
+            let files = m.files.saturating_sub(session.files + session.skipped_files);
+            let bytes = m.bytes.saturating_sub(session.bytes + session.skipped_bytes);
+            if files > 0 {
+                s.push_str(&format!(", {} files ({}) not transferred", files, human_read_size(bytes)));
+            }
+        }
+
+        s
+    }
+}
+
+pub fn human_read_rate(rate: f64) -> String {
+    format!("{}/s", human_read_size(rate as u64))
+}
+
+// Like `1h02m03s`, `2m03s` or `5s`.
+pub fn human_read_time(t: Duration) -> String {
+    let secs = t.as_secs();
+    match secs {
+        s if s >= 3600 => format!("{}h{:02}m{:02}s", s / 3600, s % 3600 / 60, s % 60),
+        s if s >= 60 => format!("{}m{:02}s", s / 60, s % 60),
+        s => format!("{}s", s),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn human_read_time_test() {
+        assert_eq!(human_read_time(Duration::from_millis(5400)), "5s");
+        assert_eq!(human_read_time(Duration::from_secs(123)), "2m03s");
+        assert_eq!(human_read_time(Duration::from_secs(3723)), "1h02m03s");
+    }
+
+    #[test]
+    fn stats_progress_test() {
+        let stats = Stats::new();
+        stats.expect(Manifest { files: 10, dirs: 1, bytes: 20 << 20, ..Default::default() });
+        stats.add_bytes(4 << 20);
+        stats.skip(1, 1 << 20);
+        stats.file_done();
+
+        assert!(stats.progress().starts_with("Total: 2/10 files, 5.0MB/20.0MB"));
+        assert!(stats.summary("Sent").starts_with("Sent 1 files, 4.0MB in"));
+        assert!(stats.summary("Sent").ends_with(", 1 files skipped or failed, 8 files (15.0MB) not transferred"));
+    }
+}
@@ -0,0 +1,401 @@
+use anyhow::{anyhow, Result};
+use async_std::net::{TcpListener, TcpStream};
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::File;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use super::conn::Conn;
+use super::currentfile;
+use super::instruction::{Instruction, Operation};
+use super::interrupt;
+use super::limit::Limiter;
+use super::message::{self, Message};
+use super::meta;
+use super::relay;
+use super::stats::{Meter, Stats};
+use super::utils;
+
+// Max data connections besides the control connection.
+pub const MAX_STREAMS: u8 = 16;
+
+// Size of the join request: 1 byte index followed by the token.
+const JOIN_SIZE: usize = 33;
+
+// Ranges waiting for each data connection.
+const QUEUE_SIZE: usize = 2;
+
+// How the data connections are made: the same way as the control connection,
+// so they get through the same firewalls and relays.
+pub enum Link {
+    Accept(Vec<TcpListener>),
+    Connect(SocketAddr),
+    Relay(String),      // address of the relay.
+}
+
+// A range of a file for a data connection, by the id of the file's start request.
+pub struct Range {
+    pub id: u16,
+    pub offset: u64,
+    pub chunk: Vec<u8>,
+}
+
+// Hand out the ranges to the data connections, to the first one with room.
+#[derive(Clone)]
+pub struct Dispatcher {
+    queues: Vec<mpsc::Sender<Range>>,
+    next: usize,
+}
+
+// A file open for the ranges from the data connections.
+pub struct Target {
+    file: File,
+    name: OsString,
+    size: u64,
+    written: AtomicU64,
+    meter: Mutex<Meter>,
+    aborted: AtomicBool,    // given up by the sender, the ranges still on the way are dropped.
+}
+
+// Files open for ranges by the id of their start request, used in receiver.
+pub type Targets = Arc<Mutex<HashMap<u16, Arc<Target>>>>;
+
+// Open `count` data connections of the session on `control`.
+// Each one starts with a join request with its index and a token from the session secret,
+// so only the other side of this session can join. Then it's encrypted with its own keys.
+// The side which accepts or is the receiver on a relay reads the join requests.
+// Give up if they are not all there in `timeout`, like when the other side cannot make them.
+pub async fn open(link: &Link, control: &Conn, count: u8, sender: bool, timeout: Duration) -> Result<Vec<Conn>> {
+    match async_std::future::timeout(timeout, open_link(link, control, count, sender)).await {
+        Ok(conns) => conns,
+        Err(_) => Err(anyhow!("Data connections not ready in {} seconds", timeout.as_secs())),
+    }
+}
+
+async fn open_link(link: &Link, control: &Conn, count: u8, sender: bool) -> Result<Vec<Conn>> {
+    let mut conns = Vec::new();
+
+    match link {
+        Link::Accept(listeners) => {
+            let mut joined: Vec<Option<Conn>> = (0..count).map(|_| None).collect();
+            while joined.iter().any(|c| c.is_none()) {
+                let (tcp, addr) = accept_any(listeners).await?;
+                let mut conn = Conn::new(tcp);
+                let reading = async_std::future::timeout(Duration::from_secs(10), read_join(&mut conn, control, count));
+                match reading.await {
+                    Ok(Ok(index)) if joined[index as usize].is_none() => {
+                        conn.encrypt(&control.stream_secret(index)?, sender)?;
+                        joined[index as usize] = Some(conn);
+                    },
+                    Ok(Ok(index)) => log::info!("Drop data connection {} joined again from {}", index, addr),
+                    Ok(Err(e)) => log::info!("Drop data connection from {}: {}", addr, e),
+                    Err(_) => log::info!("Drop data connection from {}: no join request in time", addr),
+                }
+            }
+
+            conns.extend(joined.into_iter().flatten());
+        },
+        Link::Connect(addr) => {
+            for index in 0..count {
+                let mut conn = Conn::new(TcpStream::connect(addr).await?);
+                send_join(&mut conn, control, index).await?;
+                conn.encrypt(&control.stream_secret(index)?, sender)?;
+                conns.push(conn);
+            }
+        },
+        Link::Relay(addr) => {
+            for index in 0..count {
+                let key = blake3::keyed_hash(&control.stream_secret(index)?, b"isend relay");
+                let mut conn = relay::connect(addr, key.as_bytes(), sender).await?;
+                if sender {
+                    send_join(&mut conn, control, index).await?;
+                } else if read_join(&mut conn, control, count).await? != index {
+                    return Err(anyhow!("Invalid data connection on relay"));
+                }
+                conn.encrypt(&control.stream_secret(index)?, sender)?;
+                conns.push(conn);
+            }
+        },
+    }
+
+    Ok(conns)
+}
+
+// Accept a connection from whichever listener gets one first.
+async fn accept_any(listeners: &[TcpListener]) -> Result<(TcpStream, SocketAddr)> {
+    let accepts = listeners.iter().map(|l| Box::pin(l.accept()));
+    let (result, _, _) = futures::future::select_all(accepts).await;
+
+    Ok(result?)
+}
+
+async fn send_join(conn: &mut Conn, control: &Conn, index: u8) -> Result<()> {
+    let mut content = vec![index];
+    content.extend_from_slice(token(control, index)?.as_bytes());
+
+    utils::send_ins_bytes(conn, 0, Operation::JoinStream, &content).await
+}
+
+// Return the index of the data connection if the token is right.
+async fn read_join(conn: &mut Conn, control: &Conn, count: u8) -> Result<u8> {
+    let ins = utils::recv_ins(conn).await?;
+    if ins.operation != Operation::JoinStream || ins.length as usize != JOIN_SIZE {
+        return Err(anyhow!("Expecting join request"));
+    }
+
+    let buf = utils::recv_content(conn, JOIN_SIZE).await?;
+    let index = buf[0];
+    if index >= count {
+        return Err(anyhow!("Invalid data connection index {}", index));
+    }
+
+    // The comparison of blake3 hashes takes constant time.
+    let mut tag = [0u8; 32];
+    tag.copy_from_slice(&buf[1..]);
+    if token(control, index)? != blake3::Hash::from(tag) {
+        return Err(anyhow!("Invalid token"));
+    }
+
+    Ok(index)
+}
+
+fn token(control: &Conn, index: u8) -> Result<blake3::Hash> {
+    Ok(blake3::keyed_hash(&control.stream_secret(index)?, b"isend join"))
+}
+
+impl Dispatcher {
+    // Send the ranges on the data connections from separate tasks, so they go in parallel.
+    // Once interrupted, the session is being aborted on the control connection, which tells why.
+    pub fn new(conns: Vec<Conn>, compress: bool) -> Self {
+        let mut queues = Vec::new();
+        for mut conn in conns {
+            let (tx, mut rx) = mpsc::channel::<Range>(QUEUE_SIZE);
+            async_std::task::spawn(async move {
+                while let Some(r) = rx.next().await {
+                    match utils::send_range(&mut conn, r.id, r.offset, &r.chunk, compress).await {
+                        Err(_) if interrupt::is_set() => return,
+                        Err(e) => message::send_msg(Message::Fatal(format!("Data connection lost: {}", e))),
+                        Ok(()) => (),
+                    }
+                }
+            });
+            queues.push(tx);
+        }
+
+        Dispatcher { queues, next: 0 }
+    }
+
+    // Wait for the next data connection in turn only if all of them are busy.
+    pub async fn send(&mut self, range: Range) -> Result<()> {
+        let mut range = range;
+        let count = self.queues.len();
+
+        for i in 0..count {
+            let queue = &mut self.queues[(self.next + i) % count];
+            match queue.try_send(range) {
+                Ok(()) => {
+                    self.next = (self.next + i + 1) % count;
+                    return Ok(());
+                },
+                Err(e) if e.is_full() => range = e.into_inner(),
+                Err(_) => return Err(anyhow!("Data connection closed")),
+            }
+        }
+
+        let queue = &mut self.queues[self.next];
+        self.next = (self.next + 1) % count;
+        queue.send(range).await.map_err(|_| anyhow!("Data connection closed"))
+    }
+}
+
+impl Target {
+    pub fn new(file: File, name: OsString, size: u64) -> Self {
+        Target { file, name, size, written: AtomicU64::new(0), meter: Mutex::default(), aborted: AtomicBool::default() }
+    }
+}
+
+// Give up the file of `id`, or all files if None, and return the bytes written to them.
+// They stay in the targets, so the ranges sent before the abort are not taken for a broken connection.
+pub fn abort(targets: &Targets, id: Option<u16>) -> u64 {
+    let mut written = 0;
+    for (_, target) in targets.lock().unwrap().iter().filter(|(i, _)| id.is_none_or(|id| **i == id)) {
+        target.aborted.store(true, Ordering::SeqCst);
+        written += target.written.load(Ordering::SeqCst);
+    }
+
+    written
+}
+
+// Whether any file is still waiting for ranges.
+fn busy(targets: &Targets) -> bool {
+    targets.lock().unwrap().values().any(|t| !t.aborted.load(Ordering::SeqCst))
+}
+
+// Write the ranges from the data connections to their files until the connection closes.
+// It's normal at the end of the session, but not with files still open,
+// unless the sender aborts the session, which may come a bit later on the control connection.
+// They share the limiter, so the limit is for all of them together.
+pub fn spawn_readers(conns: Vec<Conn>, targets: &Targets, limiter: &Limiter, stats: &Stats) {
+    for mut conn in conns {
+        let targets = targets.clone();
+        let limiter = limiter.clone();
+        let stats = stats.clone();
+        async_std::task::spawn(async move {
+            loop {
+                let result = match utils::recv_ins(&mut conn).await {
+                    Ok(ins) => {
+                        limiter.take(ins.length as usize).await;
+                        recv_range(&mut conn, &ins, &targets, &stats).await
+                    },
+                    Err(e) if busy(&targets) => {
+                        async_std::task::sleep(Duration::from_secs(1)).await;
+                        match busy(&targets) {
+                            true => Err(e),
+                            false => return,
+                        }
+                    },
+                    Err(_) => return,
+                };
+
+                if let Err(e) = result {
+                    message::send_msg(Message::Fatal(format!("Data connection lost: {}", e)));
+                }
+            }
+        });
+    }
+}
+
+async fn recv_range(conn: &mut Conn, ins: &Instruction, targets: &Targets, stats: &Stats) -> Result<()> {
+    if ins.operation != Operation::SendFileRange {
+        return Err(anyhow!("Unknown instruction on data connection"));
+    }
+
+    let (offset, chunk) = utils::recv_range(conn, ins).await?;
+    let target = targets.lock().unwrap().get(&ins.id).cloned()
+        .ok_or_else(|| anyhow!("Range for unknown file"))?;
+    // The offset comes from the other side, so it must not wrap around past the end.
+    if offset.checked_add(chunk.len() as u64).is_none_or(|end| end > target.size) {
+        return Err(anyhow!("Range out of file"));
+    }
+    if target.aborted.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    write_at(&target.file, &chunk, offset)?;
+    let written = target.written.fetch_add(chunk.len() as u64, Ordering::SeqCst) + chunk.len() as u64;
+    stats.add_bytes(chunk.len() as u64);
+    let rate = {
+        let mut meter = target.meter.lock().unwrap();
+        meter.update(written);
+        meter.describe(Some(target.size))
+    };
+    message::send_msg(Message::Progress(format!("File: \"{}\"\t\tProgress: {}/{}, {}\t\t{}", meta::display_name(&target.name),
+        currentfile::human_read_size(written), currentfile::human_read_size(target.size), rate, stats.progress())));
+
+    Ok(())
+}
+
+// Wait for the data connections to write `expected` bytes to the file.
+pub async fn wait(targets: &Targets, id: u16, expected: u64) -> Result<()> {
+    let target = targets.lock().unwrap().get(&id).cloned()
+        .ok_or_else(|| anyhow!("File not open for ranges"))?;
+
+    while target.written.load(Ordering::SeqCst) < expected {
+        interrupt::check()?;
+        async_std::task::sleep(Duration::from_millis(5)).await;
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn write_at(file: &File, buf: &[u8], offset: u64) -> std::io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn write_at(file: &File, buf: &[u8], offset: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    let (mut buf, mut offset) = (buf, offset);
+    while !buf.is_empty() {
+        let n = file.seek_write(buf, offset)?;
+        buf = &buf[n..];
+        offset += n as u64;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn pair() -> (Conn, Conn) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (s, r) = futures::join!(TcpStream::connect(addr), listener.accept());
+        let (mut s, mut r) = (Conn::new(s.unwrap()), Conn::new(r.unwrap().0));
+        s.encrypt(b"shared secret", true).unwrap();
+        r.encrypt(b"shared secret", false).unwrap();
+
+        (s, r)
+    }
+
+    #[async_std::test]
+    async fn open_streams_test() {
+        let (s_control, r_control) = pair().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let accepting = async_std::task::spawn(async move {
+            open(&Link::Accept(vec![listener]), &r_control, 2, false, Duration::from_secs(10)).await.unwrap()
+        });
+
+        // A connection without the session secret is dropped.
+        let mut stranger = Conn::new(TcpStream::connect(addr).await.unwrap());
+        utils::send_ins_bytes(&mut stranger, 0, Operation::JoinStream, &[0u8; JOIN_SIZE]).await.unwrap();
+
+        let mut s = open(&Link::Connect(addr), &s_control, 2, true, Duration::from_secs(10)).await.unwrap();
+        let mut r = accepting.await;
+        assert_eq!(r.len(), 2);
+
+        utils::send_range(&mut s[1], 7, 1024, &b"range ".repeat(100), true).await.unwrap();
+        let ins = utils::recv_ins(&mut r[1]).await.unwrap();
+        assert_eq!((ins.id, ins.compressed), (7, true));
+        assert_eq!(utils::recv_range(&mut r[1], &ins).await.unwrap(), (1024, b"range ".repeat(100)));
+    }
+
+    #[async_std::test]
+    async fn range_out_of_file_test() {
+        let (mut s, mut r) = pair().await;
+        let path = std::env::temp_dir().join(format!("isend_range_{}", std::process::id()));
+        let file = std::fs::OpenOptions::new().write(true).create(true).truncate(true).open(&path).unwrap();
+        let targets = Targets::default();
+        targets.lock().unwrap().insert(7, Arc::new(Target::new(file, OsString::from("range"), 10)));
+
+        // An offset which wraps around to the start of the file is refused as well.
+        for offset in [8, u64::MAX - 1] {
+            utils::send_range(&mut s, 7, offset, b"four", false).await.unwrap();
+            let ins = utils::recv_ins(&mut r).await.unwrap();
+            assert!(recv_range(&mut r, &ins, &targets, &Stats::new()).await.is_err());
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[async_std::test]
+    async fn open_streams_timeout_test() {
+        let (_s_control, r_control) = pair().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        // The other side never makes its data connections.
+        match open(&Link::Accept(vec![listener]), &r_control, 2, false, Duration::from_millis(200)).await {
+            Err(e) => assert!(e.to_string().contains("not ready")),
+            Ok(_) => panic!("Data connections opened without the other side"),
+        }
+    }
+}
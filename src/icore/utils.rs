@@ -1,10 +1,15 @@
-use anyhow::Result;
-use async_std::prelude::*;
-use async_std::net::TcpStream;
+use anyhow::{anyhow, Result};
+use std::convert::TryInto;
+use std::time::Duration;
+use super::conn::Conn;
+use super::hello;
 use super::instruction::{Instruction, INS_SIZE, Operation};
 
+// Max size of a chunk of file content.
+pub const MAX_CHUNK: usize = 0x200000;
+
 // Send instruction along with its content to target.
-pub async fn send_ins(stream: &mut TcpStream, id: u16, 
+pub async fn send_ins(stream: &mut Conn, id: u16,
     operation: Operation, content: Option<&String>) -> Result<()> {
 
     let mut ins = Instruction {id, operation, ..Default::default()};
@@ -12,7 +17,7 @@ pub async fn send_ins(stream: &mut TcpStream, id: u16,
         Some(c) => {
             ins.buffer = true;
             ins.length = c.len() as u32;
-            send(stream, &ins, Some(Box::new(c.as_bytes()))).await?;
+            send(stream, &ins, Some(c.as_bytes())).await?;
         },
         None => {
             send(stream, &ins, None).await?;
@@ -22,47 +27,180 @@ pub async fn send_ins(stream: &mut TcpStream, id: u16,
     Ok(())
 }
 
-pub async fn send_ins_bytes(stream: &mut TcpStream, id: u16, 
-    operation: Operation, content: &Vec<u8>) -> Result<()> {
-    let ins = Instruction {id, operation, buffer: true, length: content.len() as u32};
+pub async fn send_ins_bytes(stream: &mut Conn, id: u16,
+    operation: Operation, content: &[u8]) -> Result<()> {
+    let ins = Instruction {id, operation, buffer: true, length: content.len() as u32, ..Default::default()};
 
-    send(stream, &ins, Some(Box::new(&content))).await?;
+    send(stream, &ins, Some(content)).await?;
 
     Ok(())
 }
 
-// Helper function for send_ins().
-async fn send(stream: &mut TcpStream, ins: &Instruction,
-    content: Option<Box<&[u8]>>) -> Result<()> {
+// Send a chunk of file content or archive stream, compressed if `compress` is set and it gets smaller.
+// Data like JPEGs and archives doesn't shrink, so it's sent as it is.
+pub async fn send_chunk(stream: &mut Conn, id: u16, operation: Operation, chunk: &[u8], compress: bool)
+    -> Result<()> {
+    if compress {
+        let packed = lz4_flex::compress_prepend_size(chunk);
+        if packed.len() < chunk.len() {
+            let ins = Instruction {id, operation, buffer: true,
+                compressed: true, length: packed.len() as u32};
+            return send(stream, &ins, Some(&packed)).await;
+        }
+    }
 
-    let buf = ins.encode();
-    stream.write_all(&buf).await?;
+    send_ins_bytes(stream, id, operation, chunk).await
+}
 
+// Send a range of a file on a data connection, with the id of the file's start request.
+// The content is 8 bytes offset followed by the chunk, which is compressed like in send_chunk().
+pub async fn send_range(stream: &mut Conn, id: u16, offset: u64, chunk: &[u8], compress: bool) -> Result<()> {
+    let mut content = offset.to_be_bytes().to_vec();
+    let packed = if compress { lz4_flex::compress_prepend_size(chunk) } else { Vec::new() };
+    let compressed = compress && packed.len() < chunk.len();
+    content.extend_from_slice(if compressed { &packed } else { chunk });
+
+    let ins = Instruction {id, operation: Operation::SendFileRange, buffer: true,
+        compressed, length: content.len() as u32};
+    send(stream, &ins, Some(&content)).await
+}
+
+// Helper function for send_ins().
+// The instruction and its content are written together,
+// so an encrypted connection sends them in one frame.
+async fn send(stream: &mut Conn, ins: &Instruction,
+    content: Option<&[u8]>) -> Result<()> {
+
+    let mut buf = ins.encode().to_vec();
     if let Some(s) = content {
-        stream.write_all(&s).await?;
+        buf.extend_from_slice(s);
     }
 
+    stream.write_all(&buf).await?;
+
     log::debug!("Instruction sent: {:?}", &ins);
 
     Ok(())
 }
 
-// Receive instruction from the stream and decode it
-pub async fn recv_ins(stream: &mut TcpStream) -> Result<Instruction> {
-    let mut buf = Vec::with_capacity(INS_SIZE);
-    stream.by_ref().take(INS_SIZE as u64).read_to_end(&mut buf).await?;
-    let ins = Instruction::decode(&buf)?;
+// Receive instruction from the stream and decode it, heartbeats are skipped.
+pub async fn recv_ins(stream: &mut Conn) -> Result<Instruction> {
+    loop {
+        let buf = stream.read_exact(INS_SIZE).await?;
+        let ins = Instruction::decode(&buf)?;
+        if ins.operation != Operation::Heartbeat {
+            log::debug!("Receive instruction: {:?}", &ins);
+            return Ok(ins);
+        }
+    }
+}
+
+// Receive an instruction only if something already came, heartbeats are skipped.
+pub async fn poll_ins(stream: &mut Conn) -> Result<Option<Instruction>> {
+    while stream.has_incoming() {
+        let buf = stream.read_exact(INS_SIZE).await?;
+        let ins = Instruction::decode(&buf)?;
+        if ins.operation != Operation::Heartbeat {
+            log::debug!("Receive instruction: {:?}", &ins);
+            return Ok(Some(ins));
+        }
+    }
+
+    Ok(None)
+}
+
+// Give up the session once the other side is silent for `timeout`, and send heartbeats
+// so this side isn't taken as gone while busy. Only if the other side sends them too.
+pub fn keep_alive(stream: &mut Conn, timeout: Duration) {
+    if stream.caps & hello::CAP_HEARTBEAT == 0 {
+        return;
+    }
+
+    let beat = Instruction {operation: Operation::Heartbeat, ..Default::default()};
+    stream.set_timeout(timeout);
+    stream.keep_alive(beat.encode().to_vec());
+}
+
+pub async fn recv_content(stream: &mut Conn, length: usize) -> Result<Vec<u8>> {
+    stream.read_exact(length).await
+}
+
+// Receive a chunk of file content, decompressed if the instruction says so.
+pub async fn recv_chunk(stream: &mut Conn, ins: &Instruction) -> Result<Vec<u8>> {
+    let content = recv_content(stream, ins.length as usize).await?;
+    if !ins.compressed {
+        return Ok(content);
+    }
+
+    unpack(&content)
+}
+
+// Receive a range of a file, return its offset and the chunk.
+pub async fn recv_range(stream: &mut Conn, ins: &Instruction) -> Result<(u64, Vec<u8>)> {
+    let content = recv_content(stream, ins.length as usize).await?;
+    if content.len() < 8 {
+        return Err(anyhow!("Invalid file range"));
+    }
+
+    let offset = u64::from_be_bytes(content[..8].try_into()?);
+    let chunk = if ins.compressed { unpack(&content[8..])? } else { content[8..].to_vec() };
+
+    Ok((offset, chunk))
+}
+
+// The size in front of the compressed data comes from the other side,
+// check it before allocating.
+fn unpack(content: &[u8]) -> Result<Vec<u8>> {
+    if content.len() < 4 {
+        return Err(anyhow!("Invalid compressed chunk"));
+    }
+
+    let size = u32::from_le_bytes([content[0], content[1], content[2], content[3]]) as usize;
+    if size > MAX_CHUNK {
+        return Err(anyhow!("Compressed chunk too large"));
+    }
+
+    lz4_flex::decompress_size_prepended(content).map_err(|e| anyhow!("Invalid compressed chunk: {}", e))
+}
+
+// End the session at once with the reason, if the other side knows the abort and can still get it.
+// Then wait a moment for it to close the connection, so it reads the abort instead of a reset.
+pub async fn abort_session(stream: &mut Conn, id: u16, reason: &str) {
+    if stream.caps & hello::CAP_ABORT == 0 || stream.is_lost() {
+        return;
+    }
 
-    log::debug!("Receive instruction: {:?}", &ins);
-    
-    Ok(ins)
+    match send_ins(stream, id, Operation::AbortSession, Some(&reason.to_string())).await {
+        Ok(()) => {
+            let _ = async_std::future::timeout(Duration::from_secs(5), stream.discard()).await;
+        },
+        Err(e) => log::debug!("Cannot send abort: {}", e),
+    }
 }
 
-// Use take().read_to_end() instead of read() as the latter causes reading problem.
-pub async fn recv_content(stream: &mut TcpStream, length: usize) -> Result<Vec<u8>> {
-    let mut buf = Vec::with_capacity(length);
-    stream.by_ref().take(length as u64).read_to_end(&mut buf).await?;
+// The reason sent with an abort.
+pub async fn recv_reason(stream: &mut Conn, ins: &Instruction) -> Result<String> {
+    match ins.buffer {
+        true => Ok(String::from_utf8_lossy(&recv_content(stream, ins.length as usize).await?).to_string()),
+        false => Ok(String::new()),
+    }
+}
 
-    Ok(buf)
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unpack_test() {
+        let text = b"fn main() {}\n".repeat(1000);
+        let packed = lz4_flex::compress_prepend_size(&text);
+        assert!(packed.len() < text.len());
+        assert_eq!(unpack(&packed).unwrap(), text);
+
+        let mut bomb = packed.clone();
+        bomb[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(unpack(&bomb).is_err());
+        assert!(unpack(&packed[..2]).is_err());
+    }
 }
 
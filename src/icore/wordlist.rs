@@ -0,0 +1,37 @@
+// Words used in connection codes.
+// Short, common and easy to spell, with no two words sounding alike.
+// Keep the length 256 so each word stands for one byte.
+pub const WORDS: [&str; 256] = [
+    "acorn", "admiral", "almond", "anchor", "apple", "apron", "arrow", "atlas",
+    "autumn", "avocado", "badger", "bagel", "balloon", "bamboo", "banana", "banjo",
+    "barrel", "basket", "beacon", "beaver", "bicycle", "biscuit", "blanket", "blossom",
+    "bonfire", "bottle", "boulder", "bramble", "breeze", "bridge", "broccoli", "bucket",
+    "buffalo", "butter", "button", "cabin", "cactus", "camel", "candle", "canoe",
+    "canyon", "carpet", "carrot", "castle", "cattle", "celery", "cherry", "chimney",
+    "cinnamon", "circus", "citrus", "clover", "cobalt", "coconut", "comet", "compass",
+    "copper", "coral", "cottage", "cotton", "cougar", "coyote", "crayon", "cricket",
+    "crossword", "crystal", "cucumber", "cupcake", "cushion", "cyclone", "daisy", "dolphin",
+    "domino", "donkey", "dragon", "drizzle", "dune", "eagle", "eclipse", "elbow",
+    "elephant", "ember", "emerald", "falcon", "feather", "fiddle", "flamingo", "flannel",
+    "forest", "fossil", "fountain", "fox", "galaxy", "garden", "garlic", "gazelle",
+    "geyser", "ginger", "giraffe", "glacier", "goblet", "gorilla", "granite", "grape",
+    "gravel", "guitar", "hammer", "harbor", "harvest", "hazel", "hedgehog", "helmet",
+    "hermit", "hickory", "honey", "horizon", "hornet", "iceberg", "igloo", "indigo",
+    "island", "ivory", "jackal", "jaguar", "jasmine", "jelly", "jigsaw", "juniper",
+    "kangaroo", "kayak", "kettle", "kiwi", "koala", "ladder", "lagoon", "lantern",
+    "lava", "lemon", "leopard", "lettuce", "lily", "lizard", "lobster", "locket",
+    "magnet", "mango", "maple", "marble", "meadow", "melon", "mermaid", "meteor",
+    "mitten", "monsoon", "mosaic", "muffin", "mushroom", "napkin", "nectar", "needle",
+    "nutmeg", "oasis", "ocean", "octopus", "olive", "onion", "orbit", "orchid",
+    "otter", "oyster", "paddle", "pancake", "panther", "papaya", "parrot", "peach",
+    "peanut", "pebble", "pelican", "penguin", "pepper", "piano", "pickle", "pigeon",
+    "pillow", "pineapple", "planet", "plum", "pocket", "potato", "pretzel", "puffin",
+    "pumpkin", "puzzle", "quartz", "quilt", "rabbit", "raccoon", "radish", "rainbow",
+    "raisin", "raven", "ribbon", "river", "rocket", "saddle", "saffron", "salmon",
+    "sandal", "sapphire", "sardine", "scarf", "seagull", "shadow", "shovel", "silver",
+    "skipper", "sparrow", "spinach", "sponge", "squirrel", "stapler", "starfish", "sunflower",
+    "swallow", "tadpole", "tango", "teapot", "thimble", "thunder", "tiger", "timber",
+    "toffee", "tomato", "topaz", "tortoise", "trumpet", "tulip", "tunnel", "turnip",
+    "umbrella", "valley", "velvet", "violin", "volcano", "waffle", "walnut", "walrus",
+    "window", "winter", "wizard", "wombat", "yogurt", "zebra", "zephyr", "zucchini",
+];
@@ -3,8 +3,8 @@ mod icore;
 mod logger;
 use clap::{load_yaml, App};
 use cli::{parser::parse_input, typer};
-use icore::arg::{Arg, SendArg, RecvArg};
-use icore::{message::send_msg, message::Message, receiver, sender};
+use icore::arg::{Arg, RelayArg, SendArg, RecvArg};
+use icore::{message::send_msg, message::Message, receiver, relay, sender};
 
 #[async_std::main]
 async fn main() {
@@ -22,8 +22,9 @@ async fn main() {
     match parse_input(&m) {
         Ok(Arg::R(r)) => start_receiver(r).await,
         Ok(Arg::S(s)) => start_sender(s).await,
+        Ok(Arg::Relay(r)) => start_relay(r).await,
         Err(e) => {
-            send_msg(Message::Fatal(format!("cannot parse input: {}", e)));
+            fatal(format!("cannot parse input: {}", e)).await;
         },
     }
 }
@@ -32,7 +33,7 @@ async fn start_sender(s: SendArg) {
     log::debug!("Get sender arg:\n{:?}", &s);
 
     if let Err(e) = sender::launch(s).await {
-        send_msg(Message::Fatal(format!("in sender: {}", e)));
+        fatal(format!("in sender: {}", e)).await;
     }
 }
 
@@ -40,6 +41,21 @@ async fn start_receiver(r: RecvArg) {
     log::debug!("Get receiver arg:\n{:?}", &r);
 
     if let Err(e) = receiver::launch(r).await {
-        send_msg(Message::Fatal(format!("in receiver: {}", e)));
+        fatal(format!("in receiver: {}", e)).await;
     }
-}
\ No newline at end of file
+}
+
+async fn start_relay(r: RelayArg) {
+    log::debug!("Get relay arg:\n{:?}", &r);
+
+    if let Err(e) = relay::launch(&r.listen).await {
+        fatal(format!("in relay: {}", e)).await;
+    }
+}
+
+// The typer prints the error and exits the process.
+// Wait for it, or returning from main would exit before the messages are printed.
+async fn fatal(msg: String) {
+    send_msg(Message::Fatal(msg));
+    async_std::future::pending::<()>().await;
+}